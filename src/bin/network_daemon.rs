@@ -8,14 +8,19 @@
 //     - Is Episode Return
 //       - Compute Gradient Partial and Signal partial gradient received
 //       - The computed gradient partial may be for an older model and that will need to be compensated for (bother Aech), the Partial Gradient buffer should always be relevant to the current model
+//     - Is Perturbation Request
+//       - Take back any perturbations the worker skipped and assign its next unit of work
+//     - Is Model Received
+//       - Record the version the worker is running, resend the model if its digest does not match
 //     - Is Unknown Packet
 //       - Do nothing (log)
 //   - Invalid Packet Received
-//     - Do nothing (log)
+//     - Log and drop it, then disconnect the worker, which is corrupted or on another protocol
 //   - Worker Connected
 //     - Queue a timed signal "Worker initialisation timeout"
 //   - Worker Disconnected
 //     - Remove any active downloads for this worker
+//     - Reassign its outstanding perturbations to the next workers that ask for work
 
 // ## Signals
 //   - Signal: Worker Initialisation Timeout
@@ -36,7 +41,9 @@
 //     - There is an active worker model download of a very old version
 //       - Do nothing, the worker model download will be reset on the next Worker Model Download Chunk Signal
 //     - There is no active worker model download
-//       - Add a new transfer to the active model download list
+//       - The worker holds a recent version and every update since has a delta
+//         - Send the deltas, the worker rebuilds the latest model from its noise
+//       - Otherwise add a new transfer to the active model download list
 //       - Queue a Worker Model Download Chunk Signal
 //   - Signal: Worker Model Download Chunk
 //     - This model is now very old
@@ -45,30 +52,246 @@
 //       - Send the model chunk
 //       - The model download is complete
 //         - Drop the model download from the active model download list
+//         - If the model has been updated since, Signal Worker Model Download
 //       - The model download is not complete
 //         - Signal Worker Model Download Chunk
 //   - Signal: Model Update
+//     - Report the rewards and wall-clock time of the generation that produced it, and what
+//       became of each worker's episodes
 //     - For each worker, Signal Worker Model Download
+//   - Signal: Work Unit Timeout
+//     - If the worker still has perturbations of the unit outstanding, take them back for the
+//       next workers that ask for work, even though the worker is still connected
+//   - Signal: Generation Timeout (synchronous mode)
+//     - If the generation is still waiting on episodes, update with those that have arrived
+//     - If none have arrived, wait another timeout
 
+// ## Training modes
+//   - Asynchronous: the buffer takes episodes from any recent version and the model updates
+//     whenever it is full.
+//   - Synchronous: version N is published and the buffer only takes episodes generated with N
+//     until it is full or the generation times out, then the update publishes N+1. Episodes that
+//     arrive for N while it is being updated are dropped.
+
+use std::env;
+use std::process;
 use std::sync::Arc;
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant};
 
+use fdlib::checkpoint::{load_latest, Checkpoint, CheckpointStore};
 use fdlib::common::*;
+use fdlib::fitness_shaping::{FitnessShaping, RewardStats};
+use fdlib::gradient::{GradientBuffer, NoiseGradient};
+use fdlib::learner_config::{LearnerConfig, TrainingMode};
+use fdlib::model::load_policy;
+use fdlib::model_history::ModelHistory;
+use fdlib::noise::NoiseSpec;
+use fdlib::noise_table::{NoiseTable, NoiseTableConfig};
+use fdlib::optimizer::{Optimizer, OptimizerState};
+use fdlib::staleness::StalenessPolicy;
+use fdlib::work_schedule::{UnitId, WorkSchedule};
 use fnv::FnvHashMap;
 use message_io::network::{Endpoint, NetEvent, Transport};
 use message_io::node::{self, NodeEvent};
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoroshiro128Plus;
 
 type Handler = node::NodeHandler<NodeSignal>;
 
-const WORKER_INITIALISATION_TIMEOUT_MS: u64 = 3000;
-const PARAMETER_COUNT: usize = 1_000_000;
-
-// MAXIMUM MODEL AGE also applies to dropping received returns from worker episodes.
-#[allow(dead_code)]
-const MAXIMUM_MODEL_AGE: u32 = 10;
-
 struct ConnectedWorker {
     has_initialised: bool,
+    /// The version the worker will hold once everything sent to it has arrived.
+    model_version: Option<ModelVersion>,
+    /// The version the worker last acknowledged verifying, which it is running now. Deltas are
+    /// only sent on top of this version, once everything sent before has been acknowledged.
+    running_version: Option<ModelVersion>,
+    /// Outcomes of the episodes decided since the last generation was reported.
+    generation_counts: EpisodeCounts,
+    episode_counts: EpisodeCounts,
+}
+
+/// What became of a completed episode sent by a worker. Episodes that reach the gradient buffer
+/// only have an outcome once their batch is taken, as they may grow too old while they wait.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EpisodeOutcome {
+    /// Generated with the latest model, it counts fully.
+    Accepted,
+    /// Generated with an older model, it counts for less.
+    Discounted,
+    /// Too old or invalid, it was not used.
+    Dropped,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct EpisodeCounts {
+    accepted: u64,
+    discounted: u64,
+    dropped: u64,
+}
+
+impl EpisodeCounts {
+    fn record(&mut self, outcome: EpisodeOutcome) {
+        match outcome {
+            EpisodeOutcome::Accepted => self.accepted += 1,
+            EpisodeOutcome::Discounted => self.discounted += 1,
+            EpisodeOutcome::Dropped => self.dropped += 1,
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.accepted + self.discounted + self.dropped
+    }
+
+    fn add(&mut self, other: EpisodeCounts) {
+        self.accepted += other.accepted;
+        self.discounted += other.discounted;
+        self.dropped += other.dropped;
+    }
+}
+
+impl ConnectedWorker {
+    /// Moves the counts of the generation being reported into the worker's totals.
+    fn finish_generation(&mut self) -> EpisodeCounts {
+        let counts = std::mem::take(&mut self.generation_counts);
+        self.episode_counts.add(counts);
+        counts
+    }
+
+    /// Whether the worker has yet to acknowledge the last version sent to it.
+    fn awaiting_acknowledgement(&self) -> bool {
+        self.model_version.is_some() && self.running_version != self.model_version
+    }
+}
+
+/// A model being sent to a worker one chunk at a time. It holds its own snapshot of the model,
+/// so it carries on with the version it started with when the learner moves on.
+struct OutgoingTransfer {
+    model_version: ModelVersion,
+    model_digest: u64,
+    transfer_offset: usize,
+    model: Arc<Vec<f32>>,
+}
+
+impl OutgoingTransfer {
+    fn latest(models: &ModelHistory) -> OutgoingTransfer {
+        OutgoingTransfer {
+            model_version: models.latest_version(),
+            model_digest: models.latest_digest(),
+            transfer_offset: 0,
+            model: Arc::clone(models.latest()),
+        }
+    }
+}
+
+/// Turns batches of completed episodes into model updates.
+struct ModelUpdater {
+    gradient_buffer: GradientBuffer,
+    /// The worker that sent each buffered episode, None for episodes resumed from a checkpoint.
+    senders: Vec<Option<Endpoint>>,
+    /// Taken by the background thread while an update is in progress.
+    optimizer: Option<OptimizerState>,
+    fitness_shaping: FitnessShaping,
+    staleness: StalenessPolicy,
+    mode: TrainingMode,
+    /// Raw rewards of the batch being applied, reported once the update lands.
+    batch_rewards: Option<RewardStats>,
+    /// What became of each episode of the batch being applied, by the worker that sent it.
+    batch_outcomes: Vec<(Endpoint, EpisodeOutcome)>,
+    /// When the current generation began, as the learner started or the last update landed.
+    generation_started: Instant,
+}
+
+impl ModelUpdater {
+    fn push(&mut self, sender: Option<Endpoint>, episode: Episode) {
+        self.gradient_buffer.push(episode);
+        self.senders.push(sender);
+    }
+
+    /// Whether an episode generated with `episode_version` counts fully, for less, or is too old
+    /// to use when `latest_version` is the newest model.
+    fn outcome(
+        &self,
+        episode_version: ModelVersion,
+        latest_version: ModelVersion,
+    ) -> EpisodeOutcome {
+        match self.staleness.weight(episode_version, latest_version) {
+            Some(weight) if weight >= 1.0 => EpisodeOutcome::Accepted,
+            Some(_) => EpisodeOutcome::Discounted,
+            None => EpisodeOutcome::Dropped,
+        }
+    }
+
+    /// Takes the full buffer as a batch for updating `latest_version`, with the rewards shaped
+    /// and then discounted by age. Episodes that have grown too old while waiting are dropped.
+    fn take_batch(&mut self, latest_version: ModelVersion) -> Vec<Episode> {
+        let mut episodes = self.gradient_buffer.take();
+        let senders = std::mem::take(&mut self.senders);
+        self.batch_outcomes = episodes
+            .iter()
+            .zip(senders)
+            .filter_map(|(episode, sender)| {
+                Some((sender?, self.outcome(episode.model_version, latest_version)))
+            })
+            .collect();
+        self.staleness.retain_usable(&mut episodes, latest_version);
+        if self.mode == TrainingMode::Synchronous {
+            // The same returns give the same update whatever order they arrived in.
+            episodes.sort_by(|a, b| {
+                (
+                    a.noise_seed,
+                    a.noise_offset,
+                    a.noise_sign == NoiseSign::Negative,
+                )
+                    .cmp(&(
+                        b.noise_seed,
+                        b.noise_offset,
+                        b.noise_sign == NoiseSign::Negative,
+                    ))
+                    .then(a.reward.total_cmp(&b.reward))
+            });
+        }
+        self.batch_rewards = RewardStats::of(&episodes);
+        self.fitness_shaping.apply(&mut episodes);
+        self.staleness.discount(&mut episodes, latest_version);
+        episodes
+    }
+
+    fn is_updating(&self) -> bool {
+        self.optimizer.is_none()
+    }
+
+    /// Whether an episode generated with `episode_version` may join the batch for
+    /// `latest_version`, before staleness is considered.
+    fn accepts(&self, episode_version: ModelVersion, latest_version: ModelVersion) -> bool {
+        match self.mode {
+            TrainingMode::Asynchronous => true,
+            TrainingMode::Synchronous => episode_version == latest_version && !self.is_updating(),
+        }
+    }
+
+    /// Ends the current generation, returning the rewards of its batch and how long it took, and
+    /// recording the outcome of each of its episodes against the worker that sent it.
+    fn finish_generation(
+        &mut self,
+        connected_workers: &mut FnvHashMap<Endpoint, ConnectedWorker>,
+    ) -> (Option<RewardStats>, Duration) {
+        for (endpoint, outcome) in self.batch_outcomes.drain(..) {
+            if let Some(worker) = connected_workers.get_mut(&endpoint) {
+                worker.generation_counts.record(outcome);
+            }
+        }
+        let now = Instant::now();
+        let elapsed = now - self.generation_started;
+        self.generation_started = now;
+        (self.batch_rewards.take(), elapsed)
+    }
+}
+
+/// Hands out perturbations to workers, with seeds drawn from the learner's checkpointed RNG.
+struct WorkAssigner {
+    schedule: WorkSchedule<Endpoint>,
+    rng: Xoroshiro128Plus,
 }
 
 enum NodeSignal {
@@ -77,39 +300,203 @@ enum NodeSignal {
     WorkerHasTimedOut(Endpoint),
     NextTransferBlock(Endpoint),
     InitialiseWorker(Endpoint),
-    SendModelToWorker(Endpoint, ModelVersion),
+    SendModelToWorker(Endpoint),
     CleanupWorker(Endpoint),
+    EpisodeCompleted(Endpoint, Episode),
+    RetransmitChunk(Endpoint, ModelVersion, usize),
+    RestartTransfer(Endpoint),
+    AssignPerturbations(Endpoint),
+    WorkUnitTimeout(Endpoint, UnitId),
+    GenerationTimeout(ModelVersion),
+    ModelReceived(Endpoint, ModelVersion, u64),
+    ModelUpdated(Vec<f32>, OptimizerState, Option<ModelDelta>),
+}
+
+/// Reads the configuration, exiting with a usage or validation message if it is not usable.
+fn parse_config() -> LearnerConfig {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        print!("{}", LearnerConfig::usage());
+        process::exit(0);
+    }
+    match LearnerConfig::from_args(args) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, LearnerConfig::usage());
+            process::exit(2);
+        }
+    }
+}
+
+/// Starts training from scratch or from an initial policy, or from a checkpoint when resuming.
+fn initial_checkpoint(config: &LearnerConfig) -> Checkpoint {
+    let checkpoint = match &config.resume {
+        Some(resume_path) => match load_latest(resume_path) {
+            Ok(checkpoint) => {
+                println!("Resuming from model version {}", checkpoint.model_version);
+                checkpoint
+            }
+            Err(error) => {
+                eprintln!("Could not resume from {}: {}", resume_path.display(), error);
+                process::exit(1);
+            }
+        },
+        None => new_checkpoint(config),
+    };
+    if !checkpoint.noise_spec.is_supported() {
+        eprintln!(
+            "The checkpoint was trained with noise version {} but this build generates version {}",
+            checkpoint.noise_spec.version,
+            NoiseSpec::CURRENT.version
+        );
+        process::exit(1);
+    }
+    if let Some(parameter_count) = config.parameter_count {
+        if parameter_count != checkpoint.model.len() {
+            eprintln!(
+                "The model has {} parameters but model.parameter_count is {}",
+                checkpoint.model.len(),
+                parameter_count
+            );
+            process::exit(1);
+        }
+    }
+    checkpoint
+}
+
+fn new_checkpoint(config: &LearnerConfig) -> Checkpoint {
+    let model = match &config.init_policy {
+        Some(policy_path) => match load_policy(policy_path) {
+            Ok(model) if !model.is_empty() => {
+                println!(
+                    "Loaded initial policy of {} parameters from {}",
+                    model.len(),
+                    policy_path.display()
+                );
+                model
+            }
+            Ok(_) => {
+                eprintln!("Initial policy {} is empty", policy_path.display());
+                process::exit(1);
+            }
+            Err(error) => {
+                eprintln!("Could not load initial policy: {}", error);
+                process::exit(1);
+            }
+        },
+        None => (0..config.default_parameter_count())
+            .map(|i| i as f32)
+            .collect(),
+    };
+    if config.noise_table_size < model.len() {
+        eprintln!(
+            "training.noise_table_size {} is smaller than the model's {} parameters",
+            config.noise_table_size,
+            model.len()
+        );
+        process::exit(1);
+    }
+    let optimizer = config.optimizer_config().build(model.len());
+    let mut rng = Xoroshiro128Plus::seed_from_u64(config.seed);
+    Checkpoint {
+        model_version: 0,
+        model,
+        noise_table: NoiseTableConfig {
+            seed: rng.gen(),
+            size: config.noise_table_size,
+        },
+        noise_spec: NoiseSpec::CURRENT,
+        pending_episodes: Vec::new(),
+        rng,
+        optimizer,
+    }
+}
+
+/// Starts listening on every configured address, exiting if one cannot be bound.
+fn listen(handler: &Handler, config: &LearnerConfig) {
+    let listen_addresses = [
+        (Transport::FramedTcp, &config.tcp_listen_address),
+        (Transport::Udp, &config.udp_listen_address),
+        (Transport::Ws, &config.ws_listen_address),
+    ];
+    for (transport, address) in listen_addresses {
+        if let Some(address) = address {
+            match handler.network().listen(transport, address.as_str()) {
+                Ok((_, address)) => println!("Listening for {:?} on {}", transport, address),
+                Err(error) => {
+                    eprintln!(
+                        "Could not listen for {:?} on {}: {}",
+                        transport, address, error
+                    );
+                    process::exit(1);
+                }
+            }
+        }
+    }
 }
 
 fn main() {
-    let latest_model_version: ModelVersion = 0;
-    let _models = FnvHashMap::<ModelVersion, Arc<Vec<f32>>>::default();
-    let mut active_transfers = FnvHashMap::<Endpoint, ModelTransfer>::default();
+    let config = parse_config();
+    let checkpoint = initial_checkpoint(&config);
+    let checkpoint_store = CheckpointStore::new(
+        config.checkpoint_directory.clone(),
+        config.checkpoints_retained,
+    )
+    .expect("Creating checkpoint directory");
+
+    let mut active_transfers = FnvHashMap::<Endpoint, OutgoingTransfer>::default();
     let mut connected_workers = FnvHashMap::<Endpoint, ConnectedWorker>::default();
 
-    let mut model = Vec::<f32>::with_capacity(PARAMETER_COUNT);
-    for i in 0..PARAMETER_COUNT {
-        model.push(i as f32);
+    let mut work = WorkAssigner {
+        schedule: WorkSchedule::new(config.work_unit_size),
+        rng: checkpoint.rng,
+    };
+
+    println!(
+        "Generating noise table of {} values",
+        checkpoint.noise_table.size
+    );
+    let noise_table = Arc::new(NoiseTable::new(checkpoint.noise_table));
+
+    let optimizer_config = config.optimizer_config();
+    let optimizer = optimizer_config
+        .resume(checkpoint.optimizer, checkpoint.model.len())
+        .unwrap_or_else(|| {
+            println!(
+                "Warning: Checkpoint optimizer state does not match, starting {:?} afresh.",
+                optimizer_config.kind
+            );
+            optimizer_config.build(checkpoint.model.len())
+        });
+    let mut updater = ModelUpdater {
+        gradient_buffer: GradientBuffer::new(config.population_size),
+        senders: Vec::new(),
+        optimizer: Some(optimizer),
+        fitness_shaping: config.fitness_shaping,
+        staleness: config.staleness_policy(),
+        mode: config.training_mode,
+        batch_rewards: None,
+        batch_outcomes: Vec::new(),
+        generation_started: Instant::now(),
+    };
+    for episode in checkpoint.pending_episodes {
+        updater.push(None, episode);
     }
 
+    let mut models = ModelHistory::new(
+        checkpoint.model_version,
+        checkpoint.model,
+        config.maximum_model_age,
+    );
+
     // Create a node, the main message-io entity. It is divided in 2 parts:
     // The 'handler', used to make actions (connect, send messages, signals, stop the node...)
     // The 'listener', used to read events from the network or signals.
     let (handler, listener) = node::split::<NodeSignal>();
 
     // Listen for TCP, UDP and WebSocket messages at the same time.
-    handler
-        .network()
-        .listen(Transport::FramedTcp, "0.0.0.0:3042")
-        .unwrap();
-    handler
-        .network()
-        .listen(Transport::Udp, "0.0.0.0:3043")
-        .unwrap();
-    handler
-        .network()
-        .listen(Transport::Ws, "0.0.0.0:3044")
-        .unwrap();
+    listen(&handler, &config);
+    schedule_generation_timeout(&handler, &config, models.latest_version());
 
     // Read incoming network events.
     listener.for_each(move |event| match event {
@@ -119,10 +506,10 @@ fn main() {
                 NetEvent::Accepted(endpoint, _listener) => {
                     handle_network_connected(&handler, endpoint, &mut connected_workers);
                 }
-                NetEvent::Message(endpoint, data) => {
-                    let message = deserialise_worker_message(data);
-                    handle_worker_message(&handler, endpoint, message);
-                }
+                NetEvent::Message(endpoint, data) => match deserialise_worker_message(data) {
+                    Ok(message) => handle_worker_message(&handler, endpoint, message),
+                    Err(error) => handle_invalid_packet(&handler, endpoint, data, &error),
+                },
                 NetEvent::Disconnected(endpoint) => {
                     handle_network_disconnected(&handler, endpoint);
                 }
@@ -130,22 +517,25 @@ fn main() {
         }
         NodeEvent::Signal(signal) => match signal {
             NodeSignal::NewConnectedWorker(endpoint) => {
-                handle_new_connected_worker(&handler, endpoint, &mut connected_workers);
+                handle_new_connected_worker(&handler, &config, endpoint, &connected_workers);
             }
             NodeSignal::InitialiseWorker(endpoint) => {
                 handle_worker_initialisation(
                     &handler,
+                    &config,
                     endpoint,
-                    latest_model_version,
-                    &mut active_transfers,
+                    models.parameter_count(),
+                    noise_table.config(),
+                    &mut connected_workers,
                 );
             }
-            NodeSignal::SendModelToWorker(endpoint, latest_version) => {
+            NodeSignal::SendModelToWorker(endpoint) => {
                 begin_model_transfer_if_required(
                     &handler,
                     endpoint,
-                    latest_version,
+                    &models,
                     &mut active_transfers,
+                    &mut connected_workers,
                 );
             }
             NodeSignal::WorkerCheckTimeout(endpoint) => {
@@ -160,48 +550,294 @@ fn main() {
                     endpoint,
                     &mut connected_workers,
                     &mut active_transfers,
+                    &mut work,
                 );
             }
             NodeSignal::NextTransferBlock(endpoint) => {
-                handle_next_transfer_block(&handler, endpoint, &mut active_transfers);
+                handle_next_transfer_block(
+                    &handler,
+                    endpoint,
+                    &models,
+                    &mut active_transfers,
+                    &mut connected_workers,
+                );
+            }
+            NodeSignal::RetransmitChunk(endpoint, model_version, chunk_offset) => {
+                handle_retransmit_request(
+                    &handler,
+                    endpoint,
+                    model_version,
+                    chunk_offset,
+                    &models,
+                    &mut active_transfers,
+                );
+            }
+            NodeSignal::RestartTransfer(endpoint) => {
+                handle_restart_transfer(
+                    &handler,
+                    endpoint,
+                    &models,
+                    &mut active_transfers,
+                    &mut connected_workers,
+                );
+            }
+            NodeSignal::ModelReceived(endpoint, model_version, model_digest) => {
+                handle_model_received(
+                    &handler,
+                    endpoint,
+                    model_version,
+                    model_digest,
+                    &models,
+                    &mut connected_workers,
+                );
+            }
+            NodeSignal::AssignPerturbations(endpoint) => {
+                handle_assign_perturbations(
+                    &handler,
+                    &config,
+                    endpoint,
+                    &models,
+                    &noise_table,
+                    &mut work,
+                    &connected_workers,
+                );
+            }
+            NodeSignal::WorkUnitTimeout(endpoint, unit) => {
+                handle_work_unit_timeout(&config, endpoint, unit, &mut work);
+            }
+            NodeSignal::GenerationTimeout(model_version) => {
+                handle_generation_timeout(
+                    &handler,
+                    &config,
+                    model_version,
+                    &models,
+                    &noise_table,
+                    &mut updater,
+                );
+            }
+            NodeSignal::EpisodeCompleted(endpoint, episode) => {
+                let seed = assigned_seed(&episode, &noise_table);
+                let outcome = if work.schedule.is_revoked(endpoint, seed) {
+                    // Another worker has the seed since this one missed its deadline, using
+                    // both episodes would count the perturbation twice.
+                    Some(EpisodeOutcome::Dropped)
+                } else {
+                    work.schedule.complete(endpoint, seed);
+                    handle_episode_completed(
+                        &handler,
+                        endpoint,
+                        episode,
+                        &models,
+                        &noise_table,
+                        &mut updater,
+                    )
+                };
+                if let (Some(outcome), Some(worker)) =
+                    (outcome, connected_workers.get_mut(&endpoint))
+                {
+                    worker.generation_counts.record(outcome);
+                }
+            }
+            NodeSignal::ModelUpdated(updated_model, updated_optimizer, delta) => {
+                let latest_model_version = models.push(updated_model, delta);
+                handle_model_updated(
+                    &handler,
+                    &config,
+                    latest_model_version,
+                    &mut updater,
+                    &mut connected_workers,
+                );
+                let updated_optimizer = updater.optimizer.insert(updated_optimizer);
+                if latest_model_version.is_multiple_of(config.checkpoint_interval) {
+                    save_checkpoint(
+                        &checkpoint_store,
+                        latest_model_version,
+                        models.latest(),
+                        &noise_table,
+                        &updater.gradient_buffer,
+                        &work.rng,
+                        updated_optimizer,
+                    );
+                }
+                // A full buffer may have been waiting on this update to finish.
+                begin_model_update_if_ready(&handler, &models, &noise_table, &mut updater);
             }
         },
     });
 }
 
 fn handle_new_connected_worker(
-    _handler: &node::NodeHandler<NodeSignal>,
-    _endpoint: Endpoint,
-    _connected_workers: &mut std::collections::HashMap<
-        Endpoint,
-        ConnectedWorker,
-        std::hash::BuildHasherDefault<fnv::FnvHasher>,
-    >,
+    handler: &Handler,
+    config: &LearnerConfig,
+    endpoint: Endpoint,
+    connected_workers: &FnvHashMap<Endpoint, ConnectedWorker>,
 ) {
-    todo!()
+    if !connected_workers.contains_key(&endpoint) {
+        // Disconnected before the signal was handled.
+        return;
+    }
+    println!("Worker {} connected, waiting for initialisation.", endpoint);
+    handler.signals().send_with_timer(
+        NodeSignal::WorkerCheckTimeout(endpoint),
+        config.worker_initialisation_timeout,
+    );
 }
 
 fn handle_next_transfer_block(
-    _handler: &node::NodeHandler<NodeSignal>,
-    _endpoint: Endpoint,
-    _active_transfers: &mut std::collections::HashMap<
-        Endpoint,
-        ModelTransfer,
-        std::hash::BuildHasherDefault<fnv::FnvHasher>,
-    >,
+    handler: &Handler,
+    endpoint: Endpoint,
+    models: &ModelHistory,
+    active_transfers: &mut FnvHashMap<Endpoint, OutgoingTransfer>,
+    connected_workers: &mut FnvHashMap<Endpoint, ConnectedWorker>,
+) {
+    let transfer = match active_transfers.get_mut(&endpoint) {
+        Some(transfer) => transfer,
+        // The worker was cleaned up while the transfer was in flight.
+        None => return,
+    };
+
+    if models.get(transfer.model_version).is_none() {
+        println!(
+            "Transfer of model version {} to {} is too old, restarting with version {}.",
+            transfer.model_version,
+            endpoint,
+            models.latest_version()
+        );
+        *transfer = OutgoingTransfer::latest(models);
+    }
+
+    let chunk_offset = transfer.transfer_offset;
+    let chunk_end = (chunk_offset + MAX_F32_CHUNK_SIZE).min(transfer.model.len());
+    let message = MessageFromLearner::ParameterChunk {
+        model_version: transfer.model_version,
+        model_digest: transfer.model_digest,
+        data: ParameterChunkData {
+            chunk_hash: chunk_hash(&transfer.model[chunk_offset..chunk_end]),
+            chunk: transfer.model[chunk_offset..chunk_end].to_vec(),
+            chunk_offset,
+        },
+    };
+    let data = serialize_worker_response(message);
+    handler.network().send(endpoint, data.as_slice());
+    transfer.transfer_offset = chunk_end;
+
+    if transfer.transfer_offset == transfer.model.len() {
+        let model_version = transfer.model_version;
+        active_transfers.remove(&endpoint);
+        // The updates made while this version was being sent follow its acknowledgement.
+        if let Some(worker) = connected_workers.get_mut(&endpoint) {
+            worker.model_version = Some(model_version);
+        }
+    } else {
+        handler
+            .signals()
+            .send(NodeSignal::NextTransferBlock(endpoint));
+    }
+}
+
+fn handle_retransmit_request(
+    handler: &Handler,
+    endpoint: Endpoint,
+    model_version: ModelVersion,
+    chunk_offset: usize,
+    models: &ModelHistory,
+    active_transfers: &mut FnvHashMap<Endpoint, OutgoingTransfer>,
 ) {
-    todo!()
+    println!(
+        "Worker {} requested model version {} again from offset {}.",
+        endpoint, model_version, chunk_offset
+    );
+    match active_transfers.get_mut(&endpoint) {
+        // Rewind, the next scheduled block will resend from the corrupted chunk.
+        Some(transfer) if transfer.model_version == model_version => {
+            if chunk_offset < transfer.model.len() {
+                transfer.transfer_offset = chunk_offset;
+            }
+        }
+        // A newer model is already on its way, the worker will switch to it.
+        Some(_) => (),
+        None => {
+            // Finish sending the version the worker has started on while it is still recent.
+            let snapshot = models.get(model_version).zip(models.digest(model_version));
+            let transfer = match snapshot {
+                Some((model, model_digest)) if chunk_offset < model.len() => OutgoingTransfer {
+                    model_version,
+                    model_digest,
+                    transfer_offset: chunk_offset,
+                    model: Arc::clone(model),
+                },
+                _ => OutgoingTransfer::latest(models),
+            };
+            active_transfers.insert(endpoint, transfer);
+            handler
+                .signals()
+                .send(NodeSignal::NextTransferBlock(endpoint));
+        }
+    }
+}
+
+fn handle_restart_transfer(
+    handler: &Handler,
+    endpoint: Endpoint,
+    models: &ModelHistory,
+    active_transfers: &mut FnvHashMap<Endpoint, OutgoingTransfer>,
+    connected_workers: &mut FnvHashMap<Endpoint, ConnectedWorker>,
+) {
+    println!(
+        "Worker {} abandoned its model, sending model version {} from the start.",
+        endpoint,
+        models.latest_version()
+    );
+    if let Some(worker) = connected_workers.get_mut(&endpoint) {
+        worker.model_version = None;
+        worker.running_version = None;
+    }
+    let previous = active_transfers.insert(endpoint, OutgoingTransfer::latest(models));
+    // An active transfer already has its next block scheduled.
+    if previous.is_none() {
+        handler
+            .signals()
+            .send(NodeSignal::NextTransferBlock(endpoint));
+    }
 }
 
 fn handle_worker_cleanup(
     _handler: &Handler,
     endpoint: Endpoint,
     connected_workers: &mut FnvHashMap<Endpoint, ConnectedWorker>,
-    active_transfers: &mut FnvHashMap<Endpoint, ModelTransfer>,
+    active_transfers: &mut FnvHashMap<Endpoint, OutgoingTransfer>,
+    work: &mut WorkAssigner,
 ) {
     println!("Worker {} is being cleaned up.", endpoint);
-    connected_workers.remove(&endpoint);
+    if let Some(mut worker) = connected_workers.remove(&endpoint) {
+        worker.finish_generation();
+        let counts = worker.episode_counts;
+        println!(
+            "Worker {} sent {} episodes: {} accepted, {} discounted, {} dropped.",
+            endpoint,
+            counts.total(),
+            counts.accepted,
+            counts.discounted,
+            counts.dropped
+        );
+        match worker.running_version {
+            Some(model_version) => {
+                println!(
+                    "Worker {} was running model version {}.",
+                    endpoint, model_version
+                )
+            }
+            None => println!("Worker {} never received a model.", endpoint),
+        }
+    }
     active_transfers.remove(&endpoint);
+    let reclaimed = work.schedule.reclaim(endpoint);
+    if reclaimed > 0 {
+        println!(
+            "Reassigning {} perturbations left unevaluated by worker {}.",
+            reclaimed, endpoint
+        );
+    }
     println!("Worker {} cleaned up.", endpoint);
 }
 
@@ -211,12 +847,18 @@ fn handle_worker_timed_out(
     _connected_workers: &FnvHashMap<Endpoint, ConnectedWorker>,
 ) {
     println!("Worker {} has timed out.", endpoint);
+    disconnect_worker(handler, endpoint);
+}
+
+/// Cleans up a worker and closes its connection.
+fn disconnect_worker(handler: &Handler, endpoint: Endpoint) {
     handler
         .signals()
         .send_with_priority(NodeSignal::CleanupWorker(endpoint));
-    // TODO test that this does as expected
-    // It could do weird things for UDP, if we support it then this timeout might need to change
-    handler.network().remove(endpoint.resource_id());
+    // UDP workers share the listening socket, which must stay open for the others.
+    if endpoint.resource_id().is_remote() {
+        handler.network().remove(endpoint.resource_id());
+    }
 }
 
 fn handle_worker_timeout_check(
@@ -237,27 +879,94 @@ fn handle_worker_timeout_check(
 fn begin_model_transfer_if_required(
     handler: &Handler,
     endpoint: Endpoint,
-    latest_version: ModelVersion,
-    active_transfers: &mut FnvHashMap<Endpoint, ModelTransfer>,
+    models: &ModelHistory,
+    active_transfers: &mut FnvHashMap<Endpoint, OutgoingTransfer>,
+    connected_workers: &mut FnvHashMap<Endpoint, ConnectedWorker>,
 ) {
-    if active_transfers.get_mut(&endpoint).is_none() {
-        active_transfers.insert(
-            endpoint,
-            ModelTransfer {
-                model_version: latest_version,
-                transfer_offset: 0,
-                buffer: vec![0.0; 0],
-            },
-        );
-        handler
-            .signals()
-            .send(NodeSignal::NextTransferBlock(endpoint));
+    match active_transfers.get(&endpoint) {
+        Some(transfer) if models.get(transfer.model_version).is_none() => {
+            // The transfer will be restarted with the latest model on its next block.
+        }
+        Some(transfer) => {
+            println!(
+                "Worker {} is still receiving model version {}, it will catch up to version {} after.",
+                endpoint,
+                transfer.model_version,
+                models.latest_version()
+            );
+        }
+        None => {
+            let worker = match connected_workers.get_mut(&endpoint) {
+                Some(worker) => worker,
+                // Disconnected before the signal was handled.
+                None => return,
+            };
+            if worker.awaiting_acknowledgement() {
+                // The worker catches up once it acknowledges what it was sent.
+                return;
+            }
+            let deltas = worker
+                .running_version
+                .and_then(|model_version| models.deltas_since(model_version));
+            match deltas {
+                Some(deltas) => {
+                    for (model_version, delta) in deltas {
+                        let message = MessageFromLearner::ModelDelta {
+                            model_version,
+                            delta: delta.clone(),
+                        };
+                        let data = serialize_worker_response(message);
+                        handler.network().send(endpoint, data.as_slice());
+                    }
+                    worker.model_version = Some(models.latest_version());
+                }
+                None => {
+                    active_transfers.insert(endpoint, OutgoingTransfer::latest(models));
+                    handler
+                        .signals()
+                        .send(NodeSignal::NextTransferBlock(endpoint));
+                }
+            }
+        }
     }
 }
 
-fn send_initialise_worker_message(handler: &Handler, endpoint: Endpoint) {
+fn save_checkpoint(
+    checkpoint_store: &CheckpointStore,
+    model_version: ModelVersion,
+    model: &Arc<Vec<f32>>,
+    noise_table: &NoiseTable,
+    gradient_buffer: &GradientBuffer,
+    rng: &Xoroshiro128Plus,
+    optimizer: &OptimizerState,
+) {
+    let checkpoint = Checkpoint {
+        model_version,
+        model: model.as_ref().clone(),
+        noise_table: noise_table.config(),
+        noise_spec: NoiseSpec::CURRENT,
+        pending_episodes: gradient_buffer.episodes().to_vec(),
+        rng: rng.clone(),
+        optimizer: optimizer.clone(),
+    };
+    match checkpoint_store.save(&checkpoint) {
+        Ok(path) => println!("Saved checkpoint {}", path.display()),
+        Err(error) => println!("Warning: Could not save checkpoint: {}", error),
+    }
+}
+
+fn send_initialise_worker_message(
+    handler: &Handler,
+    config: &LearnerConfig,
+    endpoint: Endpoint,
+    parameter_count: usize,
+    noise_table: NoiseTableConfig,
+) {
     let message = MessageFromLearner::InitialiseWorker {
-        parameter_count: PARAMETER_COUNT,
+        parameter_count,
+        noise_std_dev: config.noise_std_dev,
+        noise_spec: NoiseSpec::CURRENT,
+        noise_table: Some(noise_table),
     };
     let data = serialize_worker_response(message);
     handler.network().send(endpoint, data.as_slice());
@@ -265,20 +974,57 @@ fn send_initialise_worker_message(handler: &Handler, endpoint: Endpoint) {
 
 fn handle_worker_initialisation(
     handler: &Handler,
+    config: &LearnerConfig,
     endpoint: Endpoint,
-    latest_model_version: ModelVersion,
-    _active_transfers: &mut FnvHashMap<Endpoint, ModelTransfer>,
+    parameter_count: usize,
+    noise_table: NoiseTableConfig,
+    connected_workers: &mut FnvHashMap<Endpoint, ConnectedWorker>,
 ) {
-    println!("Initialising worker");
-    send_initialise_worker_message(handler, endpoint);
-    handler.signals().send(NodeSignal::SendModelToWorker(
-        endpoint,
-        latest_model_version,
-    ));
+    match connected_workers.get_mut(&endpoint) {
+        Some(worker) => {
+            worker.has_initialised = true;
+            // Whatever the worker held before is gone once it initialises again.
+            worker.model_version = None;
+            worker.running_version = None;
+        }
+        // Timed out or disconnected before the signal was handled.
+        None => return,
+    }
+    println!("Initialising worker {}", endpoint);
+    send_initialise_worker_message(handler, config, endpoint, parameter_count, noise_table);
+    handler
+        .signals()
+        .send(NodeSignal::SendModelToWorker(endpoint));
+    handler
+        .signals()
+        .send(NodeSignal::AssignPerturbations(endpoint));
 }
 
-fn deserialise_worker_message(data: &[u8]) -> MessageFromWorker {
-    bincode::deserialize(data).unwrap()
+/// Fails for packets that are corrupted or from a worker built with another protocol.
+fn deserialise_worker_message(data: &[u8]) -> bincode::Result<MessageFromWorker> {
+    bincode::deserialize(data)
+}
+
+/// Drops a packet the learner cannot read, along with the worker that sent it.
+fn handle_invalid_packet(
+    handler: &Handler,
+    endpoint: Endpoint,
+    data: &[u8],
+    error: &bincode::Error,
+) {
+    let start: Vec<String> = data
+        .iter()
+        .take(16)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    println!(
+        "Invalid packet of {} bytes from {} ({}...): {}. Disconnecting it.",
+        data.len(),
+        endpoint,
+        start.join(" "),
+        error
+    );
+    disconnect_worker(handler, endpoint);
 }
 
 fn serialize_worker_response(response: MessageFromLearner) -> Vec<u8> {
@@ -293,11 +1039,364 @@ fn handle_worker_message(handler: &Handler, endpoint: Endpoint, message: Message
                 .send(NodeSignal::InitialiseWorker(endpoint));
         }
         MessageFromWorker::EpisodeCompleted(episode) => {
-            println!("Episode completed: {:?}", episode);
+            handler
+                .signals()
+                .send(NodeSignal::EpisodeCompleted(endpoint, episode));
+        }
+        MessageFromWorker::RequestModel => {
+            handler
+                .signals()
+                .send(NodeSignal::RestartTransfer(endpoint));
+        }
+        MessageFromWorker::RequestPerturbations => {
+            handler
+                .signals()
+                .send(NodeSignal::AssignPerturbations(endpoint));
+        }
+        MessageFromWorker::ModelReceived {
+            model_version,
+            model_digest,
+        } => {
+            handler.signals().send(NodeSignal::ModelReceived(
+                endpoint,
+                model_version,
+                model_digest,
+            ));
+        }
+        MessageFromWorker::RequestChunk {
+            model_version,
+            chunk_offset,
+        } => {
+            handler.signals().send(NodeSignal::RetransmitChunk(
+                endpoint,
+                model_version,
+                chunk_offset,
+            ));
         }
     }
 }
 
+/// Hands a worker its next unit of work, taking back any perturbations it skipped in the last.
+fn handle_assign_perturbations(
+    handler: &Handler,
+    config: &LearnerConfig,
+    endpoint: Endpoint,
+    models: &ModelHistory,
+    noise_table: &NoiseTable,
+    work: &mut WorkAssigner,
+    connected_workers: &FnvHashMap<Endpoint, ConnectedWorker>,
+) {
+    if work.schedule.unit_size() == 0 {
+        // Workers draw their own seeds.
+        return;
+    }
+    match connected_workers.get(&endpoint) {
+        Some(worker) if worker.has_initialised => (),
+        // Disconnected before the signal was handled.
+        _ => return,
+    }
+    let skipped = work.schedule.reclaim(endpoint);
+    if skipped > 0 {
+        println!(
+            "Worker {} skipped {} perturbations, reassigning them.",
+            endpoint, skipped
+        );
+    }
+
+    let parameter_count = models.parameter_count();
+    let rng = &mut work.rng;
+    let (unit, noise_seeds) = work.schedule.assign(endpoint, || {
+        // Seeds are table offsets, so each one is a distinct perturbation.
+        let draw = rng.gen();
+        noise_table
+            .offset_for(draw, parameter_count)
+            .map_or(draw, |offset| offset as u64)
+    });
+    let message = MessageFromLearner::AssignPerturbations {
+        model_version: models.latest_version(),
+        noise_std_dev: config.noise_std_dev,
+        noise_seeds,
+    };
+    let data = serialize_worker_response(message);
+    handler.network().send(endpoint, data.as_slice());
+    handler.signals().send_with_timer(
+        NodeSignal::WorkUnitTimeout(endpoint, unit),
+        config.work_unit_timeout,
+    );
+}
+
+/// Takes back what is left of a unit of work that is past its deadline, so a worker that has
+/// stalled without disconnecting does not hold on to its perturbations.
+fn handle_work_unit_timeout(
+    config: &LearnerConfig,
+    endpoint: Endpoint,
+    unit: UnitId,
+    work: &mut WorkAssigner,
+) {
+    let reclaimed = work.schedule.reclaim_unit(endpoint, unit);
+    if reclaimed > 0 {
+        println!(
+            "Worker {} did not finish {} perturbations within {:.2}s, reassigning them.",
+            endpoint,
+            reclaimed,
+            config.work_unit_timeout.as_secs_f64()
+        );
+    }
+}
+
+/// The seed a worker was assigned for the perturbation behind `episode`.
+fn assigned_seed(episode: &Episode, noise_table: &NoiseTable) -> u64 {
+    if episode.noise_seed == noise_table.seed() {
+        episode.noise_offset as u64
+    } else {
+        episode.noise_seed
+    }
+}
+
+/// Records the version a worker has verified, or sends the model again if the worker's copy
+/// does not match the learner's.
+fn handle_model_received(
+    handler: &Handler,
+    endpoint: Endpoint,
+    model_version: ModelVersion,
+    model_digest: u64,
+    models: &ModelHistory,
+    connected_workers: &mut FnvHashMap<Endpoint, ConnectedWorker>,
+) {
+    let worker = match connected_workers.get_mut(&endpoint) {
+        Some(worker) => worker,
+        // Disconnected before the signal was handled.
+        None => return,
+    };
+    match models.digest(model_version) {
+        Some(expected) if expected != model_digest => {
+            println!(
+                "Worker {} has model version {} with digest {:016x}, expected {:016x}. Sending it again.",
+                endpoint, model_version, model_digest, expected
+            );
+            handler
+                .signals()
+                .send(NodeSignal::RestartTransfer(endpoint));
+        }
+        // Versions too old to check are taken on trust, the worker verified them on arrival.
+        _ => {
+            worker.running_version = Some(model_version);
+            if worker.model_version == Some(model_version)
+                && model_version != models.latest_version()
+            {
+                // Catch up on the updates made since this version was sent.
+                handler
+                    .signals()
+                    .send(NodeSignal::SendModelToWorker(endpoint));
+            }
+        }
+    }
+}
+
+/// What makes an episode unusable with a model of `parameter_count` parameters, if anything.
+/// The reward is divided by the noise standard deviation, so a bad value from one worker would
+/// put inf or NaN into every parameter.
+fn episode_problem(episode: &Episode, parameter_count: usize) -> Option<String> {
+    if episode.noise_size != parameter_count {
+        return Some(format!(
+            "noise size {}, expected {}",
+            episode.noise_size, parameter_count
+        ));
+    }
+    if !episode.reward.is_finite() {
+        return Some(format!("reward {}", episode.reward));
+    }
+    if !(episode.noise_std_dev.is_finite() && episode.noise_std_dev > 0.0) {
+        return Some(format!(
+            "noise standard deviation {}",
+            episode.noise_std_dev
+        ));
+    }
+    None
+}
+
+/// Adds the episode to the gradient buffer, or returns why it was dropped. The outcome of a
+/// buffered episode is recorded once its batch is taken.
+fn handle_episode_completed(
+    handler: &Handler,
+    endpoint: Endpoint,
+    episode: Episode,
+    models: &ModelHistory,
+    noise_table: &Arc<NoiseTable>,
+    updater: &mut ModelUpdater,
+) -> Option<EpisodeOutcome> {
+    if let Some(problem) = episode_problem(&episode, models.parameter_count()) {
+        println!(
+            "Worker {} sent an episode with {}. Dropping it.",
+            endpoint, problem
+        );
+        return Some(EpisodeOutcome::Dropped);
+    }
+    if !updater.accepts(episode.model_version, models.latest_version()) {
+        // Not part of the generation being collected.
+        return Some(EpisodeOutcome::Dropped);
+    }
+    if updater.outcome(episode.model_version, models.latest_version()) == EpisodeOutcome::Dropped {
+        // Too old to be worth using, the worker will catch up with the next transfer.
+        return Some(EpisodeOutcome::Dropped);
+    }
+
+    if updater.gradient_buffer.is_full() {
+        // The previous buffer is still being applied and this one has filled up as well.
+        println!(
+            "Warning: Gradient update is taking longer than expected, dropping episode from {}.",
+            endpoint
+        );
+        return Some(EpisodeOutcome::Dropped);
+    }
+    updater.push(Some(endpoint), episode);
+    begin_model_update_if_ready(handler, models, noise_table, updater);
+    None
+}
+
+/// Starts an update once the buffer is full, unless the optimizer is still busy with the last one.
+fn begin_model_update_if_ready(
+    handler: &Handler,
+    models: &ModelHistory,
+    noise_table: &Arc<NoiseTable>,
+    updater: &mut ModelUpdater,
+) {
+    if updater.gradient_buffer.is_full() {
+        begin_model_update_now(handler, models, noise_table, updater);
+    }
+}
+
+/// Starts an update with whatever the buffer holds, unless the optimizer is still busy.
+fn begin_model_update_now(
+    handler: &Handler,
+    models: &ModelHistory,
+    noise_table: &Arc<NoiseTable>,
+    updater: &mut ModelUpdater,
+) {
+    if let Some(optimizer) = updater.optimizer.take() {
+        begin_model_update(
+            handler,
+            optimizer,
+            Arc::clone(models.latest()),
+            Arc::clone(noise_table),
+            updater.take_batch(models.latest_version()),
+        );
+    }
+}
+
+fn begin_model_update(
+    handler: &Handler,
+    mut optimizer: OptimizerState,
+    model: Arc<Vec<f32>>,
+    noise_table: Arc<NoiseTable>,
+    episodes: Vec<Episode>,
+) {
+    let handler = handler.clone();
+    thread::spawn(move || {
+        let noise_gradient = NoiseGradient::from_episodes(&episodes, model.len());
+        let gradient = noise_gradient.expand(model.len(), Some(&noise_table));
+        let mut updated_model = model.as_ref().clone();
+        optimizer.step(&mut updated_model, &gradient);
+        let delta = optimizer.stateless_step().map(|step| ModelDelta {
+            gradient: noise_gradient,
+            step,
+            model_digest: model_digest(&updated_model),
+        });
+        handler
+            .signals()
+            .send(NodeSignal::ModelUpdated(updated_model, optimizer, delta));
+    });
+}
+
+fn handle_model_updated(
+    handler: &Handler,
+    config: &LearnerConfig,
+    latest_model_version: ModelVersion,
+    updater: &mut ModelUpdater,
+    connected_workers: &mut FnvHashMap<Endpoint, ConnectedWorker>,
+) {
+    println!("Model updated to version {}", latest_model_version);
+    let (rewards, elapsed) = updater.finish_generation(connected_workers);
+    if let Some(rewards) = rewards {
+        println!(
+            "Generation {} took {:.2}s: {} episodes, reward mean {:.4}, max {:.4}, std dev {:.4}",
+            latest_model_version - 1,
+            elapsed.as_secs_f64(),
+            rewards.count,
+            rewards.mean,
+            rewards.max,
+            rewards.std_dev
+        );
+    }
+    for (endpoint, worker) in connected_workers.iter_mut() {
+        let counts = worker.finish_generation();
+        if counts.total() > 0 {
+            println!(
+                "Worker {} in generation {}: {} accepted, {} discounted, {} dropped.",
+                endpoint,
+                latest_model_version - 1,
+                counts.accepted,
+                counts.discounted,
+                counts.dropped
+            );
+        }
+    }
+    schedule_generation_timeout(handler, config, latest_model_version);
+    for (endpoint, worker) in connected_workers.iter() {
+        if worker.has_initialised {
+            handler
+                .signals()
+                .send(NodeSignal::SendModelToWorker(*endpoint));
+        }
+    }
+}
+
+/// In synchronous mode, limits how long the generation of `model_version` waits for episodes.
+fn schedule_generation_timeout(
+    handler: &Handler,
+    config: &LearnerConfig,
+    model_version: ModelVersion,
+) {
+    if config.training_mode == TrainingMode::Synchronous {
+        handler.signals().send_with_timer(
+            NodeSignal::GenerationTimeout(model_version),
+            config.generation_timeout,
+        );
+    }
+}
+
+/// Ends a synchronous generation that ran out of time with the episodes it has, or gives it
+/// longer if none have arrived.
+fn handle_generation_timeout(
+    handler: &Handler,
+    config: &LearnerConfig,
+    model_version: ModelVersion,
+    models: &ModelHistory,
+    noise_table: &Arc<NoiseTable>,
+    updater: &mut ModelUpdater,
+) {
+    if model_version != models.latest_version() || updater.is_updating() {
+        // The generation already ended with a full batch.
+        return;
+    }
+    if updater.gradient_buffer.is_empty() {
+        println!(
+            "Generation {} has no episodes yet, waiting another {:.2}s.",
+            model_version,
+            config.generation_timeout.as_secs_f64()
+        );
+        schedule_generation_timeout(handler, config, model_version);
+        return;
+    }
+    println!(
+        "Generation {} timed out with {} of {} episodes, updating with those.",
+        model_version,
+        updater.gradient_buffer.len(),
+        config.population_size
+    );
+    begin_model_update_now(handler, models, noise_table, updater);
+}
+
 fn handle_network_connected(
     handler: &Handler,
     endpoint: Endpoint,
@@ -308,17 +1407,16 @@ fn handle_network_connected(
         endpoint,
         ConnectedWorker {
             has_initialised: false,
+            model_version: None,
+            running_version: None,
+            generation_counts: EpisodeCounts::default(),
+            episode_counts: EpisodeCounts::default(),
         },
     );
 
     handler
         .signals()
         .send(NodeSignal::NewConnectedWorker(endpoint));
-
-    handler.signals().send_with_timer(
-        NodeSignal::WorkerCheckTimeout(endpoint),
-        Duration::from_millis(WORKER_INITIALISATION_TIMEOUT_MS),
-    );
 }
 
 fn handle_network_disconnected(handler: &node::NodeHandler<NodeSignal>, endpoint: Endpoint) {
@@ -327,3 +1425,148 @@ fn handle_network_disconnected(handler: &node::NodeHandler<NodeSignal>, endpoint
         .signals()
         .send_with_priority(NodeSignal::CleanupWorker(endpoint));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        deserialise_worker_message, episode_problem, ConnectedWorker, EpisodeCounts, ModelUpdater,
+    };
+    use fdlib::common::{Episode, MessageFromWorker, NoiseSign};
+    use fdlib::fitness_shaping::FitnessShaping;
+    use fdlib::gradient::GradientBuffer;
+    use fdlib::learner_config::TrainingMode;
+    use fdlib::optimizer::OptimizerConfig;
+    use fdlib::staleness::{StalenessPolicy, StalenessWeighting};
+    use fnv::FnvHashMap;
+    use message_io::network::{Endpoint, ResourceId, Transport};
+    use std::time::Instant;
+
+    fn episode(noise_std_dev: f32, reward: f32) -> Episode {
+        Episode {
+            model_version: 0,
+            noise_seed: 1,
+            noise_offset: 0,
+            noise_size: 10,
+            noise_std_dev,
+            noise_sign: NoiseSign::Positive,
+            reward,
+        }
+    }
+
+    #[test]
+    fn unusable_episodes_are_rejected() {
+        assert_eq!(episode_problem(&episode(0.02, 1.0), 10), None);
+        assert!(episode_problem(&episode(0.02, 1.0), 11).is_some());
+        assert!(episode_problem(&episode(0.02, f32::NAN), 10).is_some());
+        for noise_std_dev in [0.0, -0.02, f32::NAN, f32::INFINITY] {
+            assert!(
+                episode_problem(&episode(noise_std_dev, 1.0), 10).is_some(),
+                "Noise standard deviation {} should be rejected.",
+                noise_std_dev
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_packets_are_errors() {
+        let episode = bincode::serialize(&MessageFromWorker::EpisodeCompleted(episode(0.02, 1.0)));
+        let episode = episode.unwrap();
+        assert!(deserialise_worker_message(&episode).is_ok());
+        let garbage: Vec<u8> = (0..64u8).map(|i| i.wrapping_mul(151)).collect();
+        for data in [
+            &[][..],
+            &[0xff; 4],
+            &[0xff; 64],
+            &garbage,
+            &episode[..episode.len() - 1],
+        ] {
+            assert!(
+                deserialise_worker_message(data).is_err(),
+                "{:?} should not be read as a message.",
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn batch_outcomes_are_recorded_per_worker() {
+        let mut updater = ModelUpdater {
+            gradient_buffer: GradientBuffer::new(4),
+            senders: Vec::new(),
+            optimizer: Some(OptimizerConfig::default().build(10)),
+            fitness_shaping: FitnessShaping::None,
+            staleness: StalenessPolicy {
+                weighting: StalenessWeighting::Linear,
+                maximum_age: 1,
+            },
+            mode: TrainingMode::Asynchronous,
+            batch_rewards: None,
+            batch_outcomes: Vec::new(),
+            generation_started: Instant::now(),
+        };
+        // A UDP client as the learner sees it, behind the listener's local resource.
+        let listener = ResourceId::from(1 << 7 | Transport::Udp.id() as usize);
+        let endpoint = Endpoint::from_listener(listener, ([127, 0, 0, 1], 3042).into());
+        let mut connected_workers = FnvHashMap::default();
+        connected_workers.insert(
+            endpoint,
+            ConnectedWorker {
+                has_initialised: true,
+                model_version: Some(5),
+                running_version: Some(5),
+                generation_counts: EpisodeCounts::default(),
+                episode_counts: EpisodeCounts::default(),
+            },
+        );
+        // Buffered while they were recent, but version 3 has aged out by the time of the batch.
+        for model_version in [5, 4, 3] {
+            let episode = Episode {
+                model_version,
+                ..episode(0.02, 1.0)
+            };
+            updater.push(Some(endpoint), episode);
+        }
+        // Resumed from a checkpoint, so it has no worker to count against.
+        let resumed = Episode {
+            model_version: 5,
+            ..episode(0.02, 1.0)
+        };
+        updater.push(None, resumed);
+
+        assert_eq!(updater.take_batch(5).len(), 3);
+        updater.finish_generation(&mut connected_workers);
+        let worker = connected_workers.get_mut(&endpoint).unwrap();
+        let expected = EpisodeCounts {
+            accepted: 1,
+            discounted: 1,
+            dropped: 1,
+        };
+        assert_eq!(worker.finish_generation(), expected);
+        assert_eq!(worker.episode_counts, expected);
+        assert_eq!(worker.finish_generation(), EpisodeCounts::default());
+    }
+
+    #[test]
+    fn deltas_wait_for_the_acknowledged_version() {
+        let mut worker = ConnectedWorker {
+            has_initialised: true,
+            model_version: None,
+            running_version: None,
+            generation_counts: EpisodeCounts::default(),
+            episode_counts: EpisodeCounts::default(),
+        };
+        assert!(
+            !worker.awaiting_acknowledgement(),
+            "A worker without a model is sent one in full."
+        );
+        worker.model_version = Some(3);
+        assert!(worker.awaiting_acknowledgement());
+        worker.running_version = Some(2);
+        assert!(
+            worker.awaiting_acknowledgement(),
+            "Versions 2 and 3 were sent together."
+        );
+        worker.running_version = Some(3);
+        assert!(!worker.awaiting_acknowledgement());
+    }
+}
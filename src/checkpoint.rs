@@ -0,0 +1,245 @@
+use crate::common::{Episode, ModelVersion};
+use crate::noise::NoiseSpec;
+use crate::noise_table::NoiseTableConfig;
+use crate::optimizer::OptimizerState;
+use fnv::FnvHasher;
+use rand_xoshiro::Xoroshiro128Plus;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+const CHECKPOINT_MAGIC: &[u8; 4] = b"FDCK";
+const CHECKPOINT_FORMAT_VERSION: u32 = 3;
+const CHECKPOINT_EXTENSION: &str = "fdck";
+// Magic, format version, payload length and payload hash.
+const HEADER_LEN: usize = 4 + 4 + 8 + 8;
+
+/// Everything the learner needs to carry on training exactly where it left off.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Checkpoint {
+    pub model_version: ModelVersion,
+    pub model: Vec<f32>,
+    pub noise_table: NoiseTableConfig,
+    /// How the noise table and the noise of the pending episodes were generated.
+    pub noise_spec: NoiseSpec,
+    /// Episodes received for the next update but not yet applied.
+    pub pending_episodes: Vec<Episode>,
+    pub rng: Xoroshiro128Plus,
+    pub optimizer: OptimizerState,
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn payload_hash(payload: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(payload);
+    hasher.finish()
+}
+
+impl Checkpoint {
+    /// Writes the checkpoint to a temporary file, syncs it and renames it over `path`, so `path`
+    /// never holds a partially written checkpoint.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let payload = bincode::serialize(self).map_err(|error| invalid_data(error.to_string()))?;
+        let mut data = Vec::with_capacity(HEADER_LEN + payload.len());
+        data.extend_from_slice(CHECKPOINT_MAGIC);
+        data.extend_from_slice(&CHECKPOINT_FORMAT_VERSION.to_le_bytes());
+        data.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        data.extend_from_slice(&payload_hash(&payload).to_le_bytes());
+        data.extend_from_slice(&payload);
+
+        let temporary_path = path.with_extension("tmp");
+        let mut file = File::create(&temporary_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&temporary_path, path)?;
+        // Make the rename itself durable, where the platform allows syncing a directory.
+        if let Some(directory) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            if let Ok(directory) = File::open(directory) {
+                let _ = directory.sync_all();
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a checkpoint, rejecting files that are truncated, corrupted or from another format.
+    pub fn load(path: &Path) -> io::Result<Checkpoint> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        if data.len() < HEADER_LEN || &data[0..4] != CHECKPOINT_MAGIC {
+            return Err(invalid_data(format!(
+                "{} is not a checkpoint",
+                path.display()
+            )));
+        }
+        let read_u32 =
+            |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let read_u64 =
+            |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        let format_version = read_u32(4);
+        if format_version != CHECKPOINT_FORMAT_VERSION {
+            return Err(invalid_data(format!(
+                "{} has checkpoint format {}, expected {}",
+                path.display(),
+                format_version,
+                CHECKPOINT_FORMAT_VERSION
+            )));
+        }
+        let payload = &data[HEADER_LEN..];
+        if payload.len() as u64 != read_u64(8) || payload_hash(payload) != read_u64(16) {
+            return Err(invalid_data(format!("{} is corrupted", path.display())));
+        }
+        bincode::deserialize(payload).map_err(|error| invalid_data(error.to_string()))
+    }
+}
+
+/// A directory of checkpoints named by model version, keeping only the most recent few.
+pub struct CheckpointStore {
+    directory: PathBuf,
+    retain: usize,
+}
+
+impl CheckpointStore {
+    pub fn new(directory: PathBuf, retain: usize) -> io::Result<CheckpointStore> {
+        fs::create_dir_all(&directory)?;
+        Ok(CheckpointStore {
+            directory,
+            retain: retain.max(1),
+        })
+    }
+
+    /// Saves the checkpoint and then removes the oldest ones beyond the retention limit.
+    pub fn save(&self, checkpoint: &Checkpoint) -> io::Result<PathBuf> {
+        let path = self.directory.join(format!(
+            "checkpoint-{:010}.{}",
+            checkpoint.model_version, CHECKPOINT_EXTENSION
+        ));
+        checkpoint.save(&path)?;
+
+        let checkpoints = list_checkpoints(&self.directory)?;
+        let expired = checkpoints.len().saturating_sub(self.retain);
+        for old_path in &checkpoints[..expired] {
+            fs::remove_file(old_path)?;
+        }
+        Ok(path)
+    }
+}
+
+/// Checkpoint files in `directory`, oldest first.
+fn list_checkpoints(directory: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut checkpoints = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().and_then(|extension| extension.to_str()) == Some(CHECKPOINT_EXTENSION) {
+            checkpoints.push(path);
+        }
+    }
+    // The zero padded version in the name makes lexical order the version order.
+    checkpoints.sort();
+    Ok(checkpoints)
+}
+
+/// Loads the checkpoint at `path`, or the newest readable checkpoint if `path` is a directory.
+/// Checkpoints that fail to load, such as one being written during a crash, are skipped.
+pub fn load_latest(path: &Path) -> io::Result<Checkpoint> {
+    if !path.is_dir() {
+        return Checkpoint::load(path);
+    }
+    for checkpoint_path in list_checkpoints(path)?.iter().rev() {
+        match Checkpoint::load(checkpoint_path) {
+            Ok(checkpoint) => return Ok(checkpoint),
+            Err(error) => println!("Skipping checkpoint: {}", error),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("No readable checkpoint in {}", path.display()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_latest, Checkpoint, CheckpointStore};
+    use crate::noise::NoiseSpec;
+    use crate::noise_table::NoiseTableConfig;
+    use crate::optimizer::{OptimizerConfig, OptimizerKind};
+    use rand::{Rng, SeedableRng};
+    use rand_xoshiro::Xoroshiro128Plus;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("fdlib-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn create_checkpoint(model_version: u32) -> Checkpoint {
+        Checkpoint {
+            model_version,
+            model: (0..1000).map(|i| i as f32 * 0.5).collect(),
+            noise_table: NoiseTableConfig { seed: 7, size: 100 },
+            noise_spec: NoiseSpec::CURRENT,
+            pending_episodes: Vec::new(),
+            rng: Xoroshiro128Plus::seed_from_u64(model_version as u64),
+            optimizer: OptimizerConfig {
+                kind: OptimizerKind::Adam,
+                ..OptimizerConfig::default()
+            }
+            .build(1000),
+        }
+    }
+
+    #[test]
+    fn checkpoint_round_trips() {
+        let directory = test_directory("round-trip");
+        let store = CheckpointStore::new(directory.clone(), 3).unwrap();
+        let mut checkpoint = create_checkpoint(4);
+        checkpoint.rng.gen::<u64>();
+        let path = store.save(&checkpoint).unwrap();
+
+        let mut loaded = Checkpoint::load(&path).unwrap();
+        assert_eq!(loaded.model_version, 4);
+        assert_eq!(loaded.model, checkpoint.model);
+        assert_eq!(loaded.noise_table, checkpoint.noise_table);
+        assert_eq!(loaded.noise_spec, checkpoint.noise_spec);
+        assert_eq!(loaded.optimizer, checkpoint.optimizer);
+        assert_eq!(
+            loaded.rng.gen::<u64>(),
+            checkpoint.rng.gen::<u64>(),
+            "The RNG should continue from where it was saved."
+        );
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn retention_and_corruption() {
+        let directory = test_directory("retention");
+        let store = CheckpointStore::new(directory.clone(), 2).unwrap();
+        let paths: Vec<PathBuf> = (1..=3)
+            .map(|version| store.save(&create_checkpoint(version)).unwrap())
+            .collect();
+        assert!(!paths[0].exists(), "Oldest checkpoint should be removed.");
+        assert!(paths[1].exists() && paths[2].exists());
+        assert_eq!(load_latest(&directory).unwrap().model_version, 3);
+
+        // Simulate a crash partway through writing the newest checkpoint.
+        let data = fs::read(&paths[2]).unwrap();
+        fs::write(&paths[2], &data[..data.len() / 2]).unwrap();
+        assert!(Checkpoint::load(&paths[2]).is_err());
+        assert_eq!(
+            load_latest(&directory).unwrap().model_version,
+            2,
+            "A truncated checkpoint should fall back to the previous one."
+        );
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
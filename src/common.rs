@@ -1,7 +1,27 @@
+use crate::gradient::NoiseGradient;
+use crate::noise::NoiseSpec;
+use crate::noise_table::NoiseTableConfig;
+use crate::optimizer::StatelessStep;
+use fnv::FnvHasher;
 use serde::{Deserialize, Serialize};
+use std::hash::Hasher;
 use std::mem::size_of;
 pub const MAX_F32_CHUNK_SIZE: usize = 62500 / size_of::<f32>();
 
+/// FNV-1a hash of the little-endian bytes of each value, used to detect corrupted chunks.
+pub fn chunk_hash(chunk: &[f32]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    for value in chunk {
+        hasher.write(&value.to_le_bytes());
+    }
+    hasher.finish()
+}
+
+/// The hash of a whole model, checked once a transfer or delta has been applied.
+pub fn model_digest(model: &[f32]) -> u64 {
+    chunk_hash(model)
+}
+
 pub enum TransferCompletion {
     NeedsMoreData {
         transfer: ModelTransfer,
@@ -12,19 +32,31 @@ pub enum TransferCompletion {
         model: Vec<f32>,
         model_version: ModelVersion,
     },
+    /// Every chunk arrived but the model does not match the digest it was sent with.
+    DigestMismatch {
+        model_version: ModelVersion,
+        expected: u64,
+        received: u64,
+    },
 }
 
 pub type ModelVersion = u32;
 pub struct ModelTransfer {
     pub model_version: ModelVersion,
+    pub model_digest: u64,
     pub transfer_offset: usize,
     pub buffer: Vec<f32>,
 }
 
 impl ModelTransfer {
-    pub fn new(model_version: ModelVersion, parameter_count: usize) -> ModelTransfer {
+    pub fn new(
+        model_version: ModelVersion,
+        model_digest: u64,
+        parameter_count: usize,
+    ) -> ModelTransfer {
         ModelTransfer {
             model_version,
+            model_digest,
             transfer_offset: 0,
             buffer: vec![0.0; parameter_count],
         }
@@ -34,13 +66,22 @@ impl ModelTransfer {
         // Destruction self
         let ModelTransfer {
             model_version,
+            model_digest,
             mut transfer_offset,
             mut buffer,
         } = self;
         let buffer_len = buffer.len();
-        buffer[self.transfer_offset..self.transfer_offset + chunk.len()].copy_from_slice(chunk);
+        buffer[transfer_offset..transfer_offset + chunk.len()].copy_from_slice(chunk);
         transfer_offset += chunk.len();
-        if self.transfer_offset == buffer.len() {
+        if transfer_offset == buffer_len {
+            let received = self::model_digest(&buffer);
+            if received != model_digest {
+                return TransferCompletion::DigestMismatch {
+                    model_version,
+                    expected: model_digest,
+                    received,
+                };
+            }
             TransferCompletion::Complete {
                 model: buffer,
                 model_version,
@@ -49,6 +90,7 @@ impl ModelTransfer {
             TransferCompletion::NeedsMoreData {
                 transfer: ModelTransfer {
                     model_version,
+                    model_digest,
                     transfer_offset,
                     buffer,
                 },
@@ -59,11 +101,30 @@ impl ModelTransfer {
     }
 }
 
+/// Which side of a noise vector a perturbation was taken from, `policy + sigma * sign * noise`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum NoiseSign {
+    Positive,
+    Negative,
+}
+
+impl NoiseSign {
+    pub fn as_f32(self) -> f32 {
+        match self {
+            NoiseSign::Positive => 1.0,
+            NoiseSign::Negative => -1.0,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Episode {
     pub model_version: ModelVersion,
+    pub noise_seed: u64,
     pub noise_offset: usize,
     pub noise_size: usize,
+    pub noise_std_dev: f32,
+    pub noise_sign: NoiseSign,
     pub reward: f32,
 }
 
@@ -71,6 +132,20 @@ pub struct Episode {
 pub enum MessageFromWorker {
     Init,
     EpisodeCompleted(Episode),
+    /// A chunk failed its hash check, resend the transfer from `chunk_offset`.
+    RequestChunk {
+        model_version: ModelVersion,
+        chunk_offset: usize,
+    },
+    /// The worker abandoned its transfer, send the latest model from the start.
+    RequestModel,
+    /// The worker has evaluated every perturbation it was assigned and is ready for more.
+    RequestPerturbations,
+    /// The worker has verified and is now running this version of the model.
+    ModelReceived {
+        model_version: ModelVersion,
+        model_digest: u64,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -80,13 +155,104 @@ pub struct ParameterChunkData {
     pub chunk_hash: u64,
 }
 
+/// The update from one model version to the next, which a worker holding the previous version
+/// can rebuild from its noise rather than receiving every parameter.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ModelDelta {
+    pub gradient: NoiseGradient,
+    pub step: StatelessStep,
+    /// `model_digest` of the updated model, a mismatch means it must be sent in full.
+    pub model_digest: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum MessageFromLearner {
+    /// Part of a model transfer, every chunk carries the `model_digest` of the whole model.
     ParameterChunk {
         model_version: ModelVersion,
+        model_digest: u64,
         data: ParameterChunkData,
     },
+    /// Applies to `model_version - 1` to give `model_version`.
+    ModelDelta {
+        model_version: ModelVersion,
+        delta: ModelDelta,
+    },
+    /// A unit of work, the worker evaluates the perturbations for exactly these seeds, once it
+    /// has a model at least as recent as `model_version`. With a noise table each seed is the
+    /// offset of the perturbation's noise in the table.
+    AssignPerturbations {
+        model_version: ModelVersion,
+        noise_std_dev: f32,
+        noise_seeds: Vec<u64>,
+    },
     InitialiseWorker {
         parameter_count: usize,
+        noise_std_dev: f32,
+        /// How the noise for every seed, and the noise table, must be generated.
+        noise_spec: NoiseSpec,
+        noise_table: Option<NoiseTableConfig>,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{chunk_hash, model_digest, ModelTransfer, TransferCompletion, MAX_F32_CHUNK_SIZE};
+
+    #[test]
+    fn chunk_hash_detects_changes() {
+        let chunk = vec![1.0, 2.0, 3.0];
+        assert_eq!(chunk_hash(&chunk), chunk_hash(&[1.0, 2.0, 3.0]));
+        assert_ne!(chunk_hash(&chunk), chunk_hash(&[1.0, 2.0, 3.0000002]));
+        assert_ne!(chunk_hash(&chunk), chunk_hash(&[2.0, 1.0, 3.0]));
+        assert_ne!(chunk_hash(&[0.0]), chunk_hash(&[-0.0]));
+    }
+
+    #[test]
+    fn transfer_completes_after_last_chunk() {
+        let model: Vec<f32> = (0..MAX_F32_CHUNK_SIZE * 2 + 10).map(|i| i as f32).collect();
+        let mut transfer = ModelTransfer::new(3, model_digest(&model), model.len());
+        for (index, chunk) in model.chunks(MAX_F32_CHUNK_SIZE).enumerate() {
+            transfer = match transfer.receive_chunk(chunk) {
+                TransferCompletion::NeedsMoreData {
+                    transfer,
+                    received,
+                    total,
+                } => {
+                    assert_eq!(received, (index + 1) * MAX_F32_CHUNK_SIZE);
+                    assert_eq!(total, model.len());
+                    transfer
+                }
+                TransferCompletion::Complete {
+                    model: received,
+                    model_version,
+                } => {
+                    assert_eq!(index, 2, "Transfer should complete on the last chunk.");
+                    assert_eq!(model_version, 3);
+                    assert_eq!(received, model);
+                    return;
+                }
+                TransferCompletion::DigestMismatch { .. } => panic!("Model failed its digest."),
+            }
+        }
+        panic!("Transfer never completed.");
+    }
+
+    #[test]
+    fn transfer_checks_the_model_digest() {
+        let model = vec![1.0, 2.0, 3.0];
+        let transfer = ModelTransfer::new(7, model_digest(&[1.0, 2.0, 4.0]), model.len());
+        match transfer.receive_chunk(&model) {
+            TransferCompletion::DigestMismatch {
+                model_version,
+                expected,
+                received,
+            } => {
+                assert_eq!(model_version, 7);
+                assert_eq!(received, model_digest(&model));
+                assert_ne!(expected, received);
+            }
+            _ => panic!("A model that does not match its digest should be rejected."),
+        }
+    }
+}
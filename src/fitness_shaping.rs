@@ -0,0 +1,250 @@
+use crate::common::Episode;
+use serde::Deserialize;
+
+/// Transforms a batch of rewards before they weight the noise, so that the size of an update does
+/// not depend on the scale of the rewards and a single outlier cannot dominate it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FitnessShaping {
+    /// Uses the rewards unchanged.
+    None,
+    /// Replaces each reward by its rank, scaled to lie in `[-0.5, 0.5]`.
+    CenteredRanks,
+    /// Subtracts the mean reward and divides by the standard deviation.
+    ZScore,
+    /// The utilities from Natural Evolution Strategies, which give the top half of the batch
+    /// log-decreasing weights and the bottom half equal negative weights.
+    NesUtility,
+}
+
+impl FitnessShaping {
+    /// Returns the shaped fitness of each reward. Tied rewards always get the same fitness, and a
+    /// batch where every reward is tied gets a fitness of zero throughout.
+    pub fn shape(self, rewards: &[f32]) -> Vec<f32> {
+        let tied = rewards.windows(2).all(|pair| pair[0] == pair[1]);
+        if tied && self != FitnessShaping::None {
+            // Nothing in the batch tells one perturbation apart from another.
+            return vec![0.0; rewards.len()];
+        }
+        match self {
+            FitnessShaping::None => rewards.to_vec(),
+            FitnessShaping::CenteredRanks => centered_ranks(rewards),
+            FitnessShaping::ZScore => z_scores(rewards),
+            FitnessShaping::NesUtility => nes_utilities(rewards),
+        }
+    }
+
+    /// Replaces the reward of each episode in a batch with its shaped fitness.
+    pub fn apply(self, episodes: &mut [Episode]) {
+        let rewards: Vec<f32> = episodes.iter().map(|episode| episode.reward).collect();
+        for (episode, fitness) in episodes.iter_mut().zip(self.shape(&rewards)) {
+            episode.reward = fitness;
+        }
+    }
+}
+
+/// Summary of the raw rewards of a batch, before any shaping.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RewardStats {
+    pub count: usize,
+    pub mean: f32,
+    pub max: f32,
+    pub std_dev: f32,
+}
+
+impl RewardStats {
+    /// None for an empty batch.
+    pub fn of(episodes: &[Episode]) -> Option<RewardStats> {
+        if episodes.is_empty() {
+            return None;
+        }
+        let count = episodes.len() as f64;
+        let mean = episodes
+            .iter()
+            .map(|episode| episode.reward as f64)
+            .sum::<f64>()
+            / count;
+        let variance = episodes
+            .iter()
+            .map(|episode| (episode.reward as f64 - mean).powi(2))
+            .sum::<f64>()
+            / count;
+        let max = episodes
+            .iter()
+            .map(|episode| episode.reward)
+            .fold(f32::NEG_INFINITY, f32::max);
+        Some(RewardStats {
+            count: episodes.len(),
+            mean: mean as f32,
+            max,
+            std_dev: variance.sqrt() as f32,
+        })
+    }
+}
+
+/// Gives the reward at each position in ascending order the value for that position, with tied
+/// rewards sharing the mean of the values for the positions they span.
+fn by_position(rewards: &[f32], values: &[f64]) -> Vec<f32> {
+    let mut order: Vec<usize> = (0..rewards.len()).collect();
+    order.sort_by(|&a, &b| rewards[a].total_cmp(&rewards[b]));
+    let mut shaped = vec![0.0; rewards.len()];
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start + 1;
+        while end < order.len() && rewards[order[end]] == rewards[order[start]] {
+            end += 1;
+        }
+        let mean = values[start..end].iter().sum::<f64>() / (end - start) as f64;
+        for &index in &order[start..end] {
+            shaped[index] = mean as f32;
+        }
+        start = end;
+    }
+    shaped
+}
+
+fn centered_ranks(rewards: &[f32]) -> Vec<f32> {
+    let ranks: Vec<f64> = (0..rewards.len()).map(|rank| rank as f64).collect();
+    let highest_rank = (rewards.len() - 1) as f32;
+    by_position(rewards, &ranks)
+        .into_iter()
+        .map(|rank| rank / highest_rank - 0.5)
+        .collect()
+}
+
+fn z_scores(rewards: &[f32]) -> Vec<f32> {
+    // Accumulated in f64 so large batches of similar rewards keep their precision.
+    let count = rewards.len() as f64;
+    let mean = rewards.iter().map(|&reward| reward as f64).sum::<f64>() / count;
+    let variance = rewards
+        .iter()
+        .map(|&reward| (reward as f64 - mean).powi(2))
+        .sum::<f64>()
+        / count;
+    let std_dev = variance.sqrt();
+    if !(std_dev.is_finite() && std_dev > 0.0) {
+        // Overflowed, or the rewards differ by less than f64 can resolve.
+        return vec![0.0; rewards.len()];
+    }
+    rewards
+        .iter()
+        .map(|&reward| ((reward as f64 - mean) / std_dev) as f32)
+        .collect()
+}
+
+fn nes_utilities(rewards: &[f32]) -> Vec<f32> {
+    let count = rewards.len();
+    // Utility by position from the best, position 1 being the highest reward.
+    let log_half = (count as f64 / 2.0 + 1.0).ln();
+    let weights: Vec<f64> = (1..=count)
+        .map(|position| (log_half - (position as f64).ln()).max(0.0))
+        .collect();
+    let total: f64 = weights.iter().sum();
+    // Listed from the lowest reward up.
+    let utilities: Vec<f64> = weights
+        .iter()
+        .rev()
+        .map(|weight| weight / total - 1.0 / count as f64)
+        .collect();
+    by_position(rewards, &utilities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FitnessShaping, RewardStats};
+    use crate::common::{Episode, NoiseSign};
+
+    const SHAPINGS: [FitnessShaping; 3] = [
+        FitnessShaping::CenteredRanks,
+        FitnessShaping::ZScore,
+        FitnessShaping::NesUtility,
+    ];
+
+    #[test]
+    fn centered_ranks_ignore_scale() {
+        let rewards = [3.0, -1.0, 1e9, 2.0, 0.0];
+        assert_eq!(
+            FitnessShaping::CenteredRanks.shape(&rewards),
+            vec![0.25, -0.5, 0.5, 0.0, -0.25],
+            "An outlier should only get the top rank."
+        );
+        assert_eq!(
+            FitnessShaping::CenteredRanks.shape(&[1.0, 5.0, 5.0, 9.0]),
+            vec![-0.5, 0.0, 0.0, 0.5],
+            "Tied rewards should share their mean rank."
+        );
+    }
+
+    #[test]
+    fn z_scores_are_standardised() {
+        let shaped = FitnessShaping::ZScore.shape(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]);
+        assert_eq!(shaped, vec![-1.5, -0.5, -0.5, -0.5, 0.0, 0.0, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn nes_utilities_favour_the_best() {
+        let shaped = FitnessShaping::NesUtility.shape(&[1.0, 4.0, 2.0, 3.0]);
+        // With 4 episodes only the top two have positive weight, ln(3) and ln(3/2).
+        let total = 3.0f32.ln() + 1.5f32.ln();
+        let expected = [
+            -0.25,
+            3.0f32.ln() / total - 0.25,
+            -0.25,
+            1.5f32.ln() / total - 0.25,
+        ];
+        for (fitness, expected) in shaped.iter().zip(expected) {
+            assert!((fitness - expected).abs() < 1e-6);
+        }
+        assert!(
+            shaped.iter().sum::<f32>().abs() < 1e-6,
+            "Utilities should sum to zero."
+        );
+
+        let tied = FitnessShaping::NesUtility.shape(&[1.0, 4.0, 4.0, 3.0]);
+        assert_eq!(tied[1], tied[2], "Tied rewards should share their utility.");
+        assert!((tied[1] - (shaped[1] + shaped[3]) / 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn tied_batches_have_zero_fitness() {
+        for shaping in SHAPINGS {
+            for rewards in [vec![], vec![7.0], vec![-3.0; 100]] {
+                let shaped = shaping.shape(&rewards);
+                assert_eq!(shaped.len(), rewards.len());
+                assert!(
+                    shaped.iter().all(|fitness| *fitness == 0.0),
+                    "{:?} should give tied rewards zero fitness, got {:?}",
+                    shaping,
+                    shaped
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn reward_stats_summarise_raw_rewards() {
+        let episodes: Vec<Episode> = [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]
+            .iter()
+            .map(|&reward| Episode {
+                model_version: 0,
+                noise_seed: 0,
+                noise_offset: 0,
+                noise_size: 1,
+                noise_std_dev: 0.02,
+                noise_sign: NoiseSign::Positive,
+                reward,
+            })
+            .collect();
+        let stats = RewardStats::of(&episodes).unwrap();
+        assert_eq!(
+            stats,
+            RewardStats {
+                count: 8,
+                mean: 5.0,
+                max: 9.0,
+                std_dev: 2.0,
+            }
+        );
+        assert_eq!(RewardStats::of(&[]), None);
+    }
+}
@@ -0,0 +1,244 @@
+use crate::common::Episode;
+use crate::kernels;
+use crate::model::{reconstruct_noise, PAR_CHUNK_SIZE};
+use crate::noise_table::NoiseTable;
+use fnv::FnvHashMap;
+use rayon::{
+    prelude::{IndexedParallelIterator, ParallelIterator},
+    slice::{ParallelSlice, ParallelSliceMut},
+};
+use serde::{Deserialize, Serialize};
+
+/// Collects completed episodes until there are enough of them to estimate a gradient.
+pub struct GradientBuffer {
+    episodes: Vec<Episode>,
+    capacity: usize,
+}
+
+impl GradientBuffer {
+    pub fn new(capacity: usize) -> GradientBuffer {
+        GradientBuffer {
+            episodes: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, episode: Episode) {
+        self.episodes.push(episode);
+    }
+
+    pub fn len(&self) -> usize {
+        self.episodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.episodes.is_empty()
+    }
+
+    pub fn episodes(&self) -> &[Episode] {
+        &self.episodes
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.episodes.len() >= self.capacity
+    }
+
+    /// Moves the collected episodes out of the buffer, leaving it empty for the next batch.
+    pub fn take(&mut self) -> Vec<Episode> {
+        std::mem::replace(&mut self.episodes, Vec::with_capacity(self.capacity))
+    }
+}
+
+/// One noise vector and the weight it carries in a gradient estimate.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct NoiseTerm {
+    pub noise_seed: u64,
+    pub noise_offset: usize,
+    pub weight: f32,
+}
+
+/// A gradient estimate kept as the weighted noise vectors it sums, which is far smaller than the
+/// gradient itself and is enough for anyone with the same noise to rebuild it exactly.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct NoiseGradient {
+    pub terms: Vec<NoiseTerm>,
+    /// Multiplies the weighted sum of the noise.
+    pub scale: f32,
+}
+
+impl NoiseGradient {
+    /// Weights each episode's noise by `sign * reward / sigma`, to be averaged over the batch.
+    /// Episodes sharing a noise vector, such as mirrored pairs, are combined into a single term
+    /// so the noise is only regenerated once. Every episode must cover the whole model.
+    pub fn from_episodes(episodes: &[Episode], parameter_count: usize) -> NoiseGradient {
+        // Keyed by (seed, offset), kept in arrival order so the summation order is reproducible.
+        let mut terms = Vec::<NoiseTerm>::with_capacity(episodes.len());
+        let mut term_index = FnvHashMap::<(u64, usize), usize>::default();
+        for episode in episodes {
+            assert_eq!(
+                episode.noise_size, parameter_count,
+                "Episode noise size does not match the model"
+            );
+            let key = (episode.noise_seed, episode.noise_offset);
+            let weight = episode.noise_sign.as_f32() * episode.reward / episode.noise_std_dev;
+            match term_index.get(&key) {
+                Some(&index) => terms[index].weight += weight,
+                None => {
+                    term_index.insert(key, terms.len());
+                    terms.push(NoiseTerm {
+                        noise_seed: episode.noise_seed,
+                        noise_offset: episode.noise_offset,
+                        weight,
+                    });
+                }
+            }
+        }
+        NoiseGradient {
+            terms,
+            scale: 1.0 / episodes.len().max(1) as f32,
+        }
+    }
+
+    /// Sums the weighted noise into a dense gradient. Noise that lies in `noise_table` is read
+    /// from it rather than regenerated.
+    pub fn expand(&self, parameter_count: usize, noise_table: Option<&NoiseTable>) -> Vec<f32> {
+        let mut gradient = vec![0.0; parameter_count];
+        if self.terms.is_empty() {
+            return gradient;
+        }
+
+        let mut noise = vec![0.0; parameter_count];
+        for term in &self.terms {
+            let table_noise = noise_table
+                .filter(|table| table.seed() == term.noise_seed)
+                .and_then(|table| table.get(term.noise_offset, parameter_count));
+            match table_noise {
+                Some(table_noise) => accumulate_scaled(&mut gradient, table_noise, term.weight),
+                None => {
+                    reconstruct_noise(term.noise_seed, term.noise_offset, &mut noise);
+                    accumulate_scaled(&mut gradient, &noise, term.weight);
+                }
+            }
+        }
+
+        let scale = self.scale;
+        gradient
+            .par_chunks_mut(PAR_CHUNK_SIZE)
+            .for_each(|chunk| chunk.iter_mut().for_each(|g| *g *= scale));
+        gradient
+    }
+}
+
+/// Estimates the gradient of the expected reward from a batch of episodes, see `NoiseGradient`.
+pub fn estimate_gradient(
+    episodes: &[Episode],
+    parameter_count: usize,
+    noise_table: Option<&NoiseTable>,
+) -> Vec<f32> {
+    NoiseGradient::from_episodes(episodes, parameter_count).expand(parameter_count, noise_table)
+}
+
+/// Takes a gradient ascent step, `model += learning_rate * gradient`.
+pub fn apply_gradient(model: &mut [f32], gradient: &[f32], learning_rate: f32) {
+    accumulate_scaled(model, gradient, learning_rate);
+}
+
+fn accumulate_scaled(target: &mut [f32], source: &[f32], scale: f32) {
+    target
+        .par_chunks_mut(PAR_CHUNK_SIZE)
+        .zip(source.par_chunks(PAR_CHUNK_SIZE))
+        .for_each(|(target_chunk, source_chunk)| {
+            kernels::add_scaled(target_chunk, source_chunk, scale);
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{Episode, NoiseSign};
+    use crate::model::reconstruct_noise;
+    use crate::noise_table::{NoiseTable, NoiseTableConfig};
+
+    const TEST_BUFFER_SIZE: usize = 100_000;
+
+    fn create_episode(noise_seed: u64, reward: f32) -> Episode {
+        Episode {
+            model_version: 0,
+            noise_seed,
+            noise_offset: 0,
+            noise_size: TEST_BUFFER_SIZE,
+            noise_std_dev: 0.5,
+            noise_sign: NoiseSign::Positive,
+            reward,
+        }
+    }
+
+    #[test]
+    fn single_episode_gradient_is_scaled_noise() {
+        let episodes = vec![create_episode(0x1234, 2.0)];
+        let gradient = super::estimate_gradient(&episodes, TEST_BUFFER_SIZE, None);
+
+        let mut noise = vec![0.0; TEST_BUFFER_SIZE];
+        reconstruct_noise(0x1234, 0, &mut noise);
+        for (g, n) in gradient.iter().zip(noise.iter()) {
+            assert!(
+                (*g - n * 4.0).abs() < 4.0 * f32::EPSILON * n.abs().max(1.0),
+                "Gradient should be reward * noise / sigma."
+            );
+        }
+    }
+
+    #[test]
+    fn opposite_rewards_cancel() {
+        let episodes = vec![create_episode(42, 1.0), create_episode(42, -1.0)];
+        let gradient = super::estimate_gradient(&episodes, TEST_BUFFER_SIZE, None);
+        assert!(
+            gradient.iter().all(|g| *g == 0.0),
+            "Equal and opposite rewards on the same noise should cancel."
+        );
+    }
+
+    #[test]
+    fn mirrored_pair_combines_into_one_term() {
+        let mut negative = create_episode(7, -1.0);
+        negative.noise_sign = NoiseSign::Negative;
+        let episodes = vec![create_episode(7, 3.0), negative];
+        let gradient = super::estimate_gradient(&episodes, TEST_BUFFER_SIZE, None);
+
+        // (3.0 - -1.0) / (2 * 0.5) = 4.0
+        let mut noise = vec![0.0; TEST_BUFFER_SIZE];
+        reconstruct_noise(7, 0, &mut noise);
+        for (g, n) in gradient.iter().zip(noise.iter()) {
+            assert!(
+                (*g - n * 4.0).abs() < 4.0 * f32::EPSILON * n.abs().max(1.0),
+                "Mirrored pair should weight the noise by the reward difference."
+            );
+        }
+    }
+
+    #[test]
+    fn noise_table_matches_reconstructed_noise() {
+        let table = NoiseTable::new(NoiseTableConfig {
+            seed: 99,
+            size: 3 * TEST_BUFFER_SIZE,
+        });
+        let mut episode = create_episode(99, 1.0);
+        episode.noise_offset = 12_345;
+        let episodes = vec![episode];
+        assert_eq!(
+            super::estimate_gradient(&episodes, TEST_BUFFER_SIZE, Some(&table)),
+            super::estimate_gradient(&episodes, TEST_BUFFER_SIZE, None),
+            "Reading noise from the table should give the same gradient as regenerating it."
+        );
+    }
+
+    #[test]
+    fn buffer_fills_and_empties() {
+        let mut buffer = super::GradientBuffer::new(2);
+        buffer.push(create_episode(1, 1.0));
+        assert!(!buffer.is_full());
+        buffer.push(create_episode(2, 1.0));
+        assert!(buffer.is_full());
+        assert_eq!(buffer.take().len(), 2);
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,471 @@
+use std::sync::OnceLock;
+
+/// An implementation of the element-wise kernels that apply noise and gradients.
+///
+/// Every kernel computes `x + y * scale` with a separate multiply and add, never a fused
+/// multiply-add, so each one gives the same output as the scalar loops bit for bit, and the
+/// Box-Muller kernels likewise match `box_muller_pair`. There is no AVX2 kernel, as the build
+/// targets Haswell and the scalar loops compile to AVX2 already.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kernel {
+    Scalar,
+    Avx512,
+    Neon,
+}
+
+impl Kernel {
+    /// The fastest kernel this CPU supports, detected on first use.
+    pub fn detect() -> Kernel {
+        static DETECTED: OnceLock<Kernel> = OnceLock::new();
+        *DETECTED.get_or_init(|| {
+            Kernel::available()
+                .last()
+                .copied()
+                .unwrap_or(Kernel::Scalar)
+        })
+    }
+
+    /// Every kernel this CPU supports, slowest first.
+    pub fn available() -> Vec<Kernel> {
+        [Kernel::Scalar, Kernel::Avx512, Kernel::Neon]
+            .into_iter()
+            .filter(|kernel| kernel.is_supported())
+            .collect()
+    }
+
+    pub fn is_supported(self) -> bool {
+        match self {
+            Kernel::Scalar => true,
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx512 => is_x86_feature_detected!("avx512f"),
+            #[cfg(target_arch = "aarch64")]
+            Kernel::Neon => std::arch::is_aarch64_feature_detected!("neon"),
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    /// `target += source * scale`, over the length of the shorter slice.
+    pub fn add_scaled(self, target: &mut [f32], source: &[f32], scale: f32) {
+        let len = target.len().min(source.len());
+        let target = target.as_mut_ptr();
+        // Safe as the slices hold `len` values and each value is read before it is written.
+        unsafe { self.run(target, target, source.as_ptr(), len, scale) }
+    }
+
+    /// `target = base + target * scale`, over the length of the shorter slice.
+    pub fn scale_and_add(self, target: &mut [f32], base: &[f32], scale: f32) {
+        let len = target.len().min(base.len());
+        let target = target.as_mut_ptr();
+        unsafe { self.run(target, base.as_ptr(), target, len, scale) }
+    }
+
+    /// `out = base + source * scale`, over the length of the shortest slice.
+    pub fn add_scaled_into(self, out: &mut [f32], base: &[f32], source: &[f32], scale: f32) {
+        let len = out.len().min(base.len()).min(source.len());
+        unsafe { self.run(out.as_mut_ptr(), base.as_ptr(), source.as_ptr(), len, scale) }
+    }
+
+    /// Transforms each whole pair of uniform values as `box_muller_pair` does, in place.
+    pub fn box_muller(self, values: &mut [f32]) {
+        let pairs = values.len() / 2;
+        // Safe as the slice holds `2 * pairs` values.
+        unsafe { self.run_box_muller(values.as_mut_ptr(), pairs) }
+    }
+
+    /// Writes `x[i] + y[i] * scale` to `out[i]` for `i < len`, where `out` may be `x` or `y`.
+    /// Kernels this CPU does not support fall back to the scalar loop.
+    unsafe fn run(self, out: *mut f32, x: *const f32, y: *const f32, len: usize, scale: f32) {
+        match self {
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx512 if self.is_supported() => run_avx512(out, x, y, len, scale),
+            #[cfg(target_arch = "aarch64")]
+            Kernel::Neon if self.is_supported() => run_neon(out, x, y, len, scale),
+            _ => run_scalar(out, x, y, len, scale),
+        }
+    }
+
+    unsafe fn run_box_muller(self, values: *mut f32, pairs: usize) {
+        match self {
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx512 if self.is_supported() => box_muller_avx512(values, pairs),
+            _ => box_muller_scalar(values, pairs),
+        }
+    }
+}
+
+/// `target += source * scale` with the fastest kernel, see `Kernel::add_scaled`.
+pub fn add_scaled(target: &mut [f32], source: &[f32], scale: f32) {
+    Kernel::detect().add_scaled(target, source, scale);
+}
+
+/// `target = base + target * scale` with the fastest kernel, see `Kernel::scale_and_add`.
+pub fn scale_and_add(target: &mut [f32], base: &[f32], scale: f32) {
+    Kernel::detect().scale_and_add(target, base, scale);
+}
+
+/// `out = base + source * scale` with the fastest kernel, see `Kernel::add_scaled_into`.
+pub fn add_scaled_into(out: &mut [f32], base: &[f32], source: &[f32], scale: f32) {
+    Kernel::detect().add_scaled_into(out, base, source, scale);
+}
+
+/// Cephes `logf` coefficients for `ln(1 + f)` with `f` in `[sqrt(0.5) - 1, sqrt(2) - 1)`.
+const LN_COEFFICIENTS: [f32; 9] = [
+    7.037_683_6e-2,
+    -1.151_461e-1,
+    1.167_699_9e-1,
+    -1.242_014_1e-1,
+    1.424_932_3e-1,
+    -1.666_805_8e-1,
+    2.000_071_5e-1,
+    -2.499_999_4e-1,
+    3.333_333e-1,
+];
+/// `ln(2)` split so that `e * LN_2_HIGH` is exact for the exponents of an f32.
+const LN_2_HIGH: f32 = 0.693_359_4;
+const LN_2_LOW: f32 = -2.121_944_4e-4;
+/// Cephes `sinf` and `cosf` coefficients for `|x| <= PI / 4`.
+const SIN_COEFFICIENTS: [f32; 3] = [-1.951_529_6e-4, 8.332_161e-3, -1.666_665_5e-1];
+const COS_COEFFICIENTS: [f32; 3] = [2.443_315_7e-5, -1.388_731_6e-3, 4.166_664_6e-2];
+/// Adding `1.5 * 2^23` rounds a value below `2^22` to the nearest integer, ties to even, and
+/// leaves that integer in the low bits of the sum.
+const ROUND_MAGIC: f32 = 12_582_912.0;
+
+/// The `NoiseSpec::V2` Box-Muller transform of one pair of uniform values in `[f32::EPSILON, 1)`.
+///
+/// It is built only from adds, multiplies, a square root and bit operations, each of which rounds
+/// the same way in every kernel, so this is the reference the vector kernels match bit for bit.
+pub(crate) fn box_muller_pair(u1: f32, u2: f32) -> (f32, f32) {
+    // ln(u1) = ln(m) + e * ln(2), with m in [sqrt(0.5), sqrt(2)).
+    let bits = u1.to_bits();
+    let mut e = (bits >> 23) as i32 - 126;
+    let m = f32::from_bits((bits & 0x007f_ffff) | 0x3f00_0000);
+    let f = if m < std::f32::consts::FRAC_1_SQRT_2 {
+        e -= 1;
+        m + m - 1.0
+    } else {
+        m - 1.0
+    };
+    let e = e as f32;
+    let z = f * f;
+    let mut p = LN_COEFFICIENTS[0];
+    for coefficient in &LN_COEFFICIENTS[1..] {
+        p = p * f + coefficient;
+    }
+    let y = p * f * z + e * LN_2_LOW - 0.5 * z;
+    let ln = f + y + e * LN_2_HIGH;
+    let magnitude = (-2.0 * ln).sqrt();
+
+    // 2 * PI * u2 = q * PI / 2 + x, with the quadrant q a whole number and |x| <= PI / 4.
+    let rounded = 4.0 * u2 + ROUND_MAGIC;
+    let quadrant = rounded.to_bits();
+    let x = (4.0 * u2 - (rounded - ROUND_MAGIC)) * std::f32::consts::FRAC_PI_2;
+    let z = x * x;
+    let sin_x =
+        ((SIN_COEFFICIENTS[0] * z + SIN_COEFFICIENTS[1]) * z + SIN_COEFFICIENTS[2]) * z * x + x;
+    let cos_x = ((COS_COEFFICIENTS[0] * z + COS_COEFFICIENTS[1]) * z + COS_COEFFICIENTS[2]) * z * z
+        - 0.5 * z
+        + 1.0;
+    let (sin, cos) = if quadrant & 1 == 0 {
+        (sin_x, cos_x)
+    } else {
+        (cos_x, sin_x)
+    };
+    // The sine is negative in quadrants 2 and 3, the cosine in 1 and 2.
+    let sin = f32::from_bits(sin.to_bits() ^ ((quadrant & 2) << 30));
+    let cos = f32::from_bits(cos.to_bits() ^ ((quadrant.wrapping_add(1) & 2) << 30));
+    (magnitude * cos, magnitude * sin)
+}
+
+unsafe fn run_scalar(out: *mut f32, x: *const f32, y: *const f32, len: usize, scale: f32) {
+    for i in 0..len {
+        *out.add(i) = *x.add(i) + *y.add(i) * scale;
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx512f")]
+unsafe fn run_avx512(out: *mut f32, x: *const f32, y: *const f32, len: usize, scale: f32) {
+    use std::arch::x86_64::*;
+    const LANES: usize = 16;
+    let scale_lanes = _mm512_set1_ps(scale);
+    let mut i = 0;
+    while i + LANES <= len {
+        let product = _mm512_mul_ps(_mm512_loadu_ps(y.add(i)), scale_lanes);
+        _mm512_storeu_ps(
+            out.add(i),
+            _mm512_add_ps(_mm512_loadu_ps(x.add(i)), product),
+        );
+        i += LANES;
+    }
+    run_scalar(out.add(i), x.add(i), y.add(i), len - i, scale);
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn run_neon(out: *mut f32, x: *const f32, y: *const f32, len: usize, scale: f32) {
+    use std::arch::aarch64::*;
+    const LANES: usize = 4;
+    let scale_lanes = vdupq_n_f32(scale);
+    let mut i = 0;
+    while i + LANES <= len {
+        // vmulq then vaddq rather than vfmaq, which would round once instead of twice.
+        let product = vmulq_f32(vld1q_f32(y.add(i)), scale_lanes);
+        vst1q_f32(out.add(i), vaddq_f32(vld1q_f32(x.add(i)), product));
+        i += LANES;
+    }
+    run_scalar(out.add(i), x.add(i), y.add(i), len - i, scale);
+}
+
+unsafe fn box_muller_scalar(values: *mut f32, pairs: usize) {
+    for i in 0..pairs {
+        let pair = values.add(2 * i);
+        (*pair, *pair.add(1)) = box_muller_pair(*pair, *pair.add(1));
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx512f")]
+unsafe fn box_muller_avx512(values: *mut f32, pairs: usize) {
+    use std::arch::x86_64::*;
+    const PAIRS: usize = 16;
+    let mut i = 0;
+    while i + PAIRS <= pairs {
+        let (low, high) = (values.add(2 * i), values.add(2 * i + PAIRS));
+        let (a, b) = (_mm512_loadu_ps(low), _mm512_loadu_ps(high));
+        let u1 = _mm512_shuffle_ps::<0b10_00_10_00>(a, b);
+        let u2 = _mm512_shuffle_ps::<0b11_01_11_01>(a, b);
+        let (z0, z1) = box_muller_lanes_avx512(u1, u2);
+        _mm512_storeu_ps(low, _mm512_unpacklo_ps(z0, z1));
+        _mm512_storeu_ps(high, _mm512_unpackhi_ps(z0, z1));
+        i += PAIRS;
+    }
+    box_muller_scalar(values.add(2 * i), pairs - i);
+}
+
+/// `box_muller_pair` for sixteen pairs, operation for operation.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx512f")]
+unsafe fn box_muller_lanes_avx512(
+    u1: std::arch::x86_64::__m512,
+    u2: std::arch::x86_64::__m512,
+) -> (std::arch::x86_64::__m512, std::arch::x86_64::__m512) {
+    use std::arch::x86_64::*;
+    let splat = |value| _mm512_set1_ps(value);
+    let splat_int = |value| _mm512_set1_epi32(value);
+
+    let bits = _mm512_castps_si512(u1);
+    let e = _mm512_sub_epi32(_mm512_srli_epi32::<23>(bits), splat_int(126));
+    let m = _mm512_or_si512(
+        _mm512_and_si512(bits, splat_int(0x007f_ffff)),
+        splat_int(0x3f00_0000),
+    );
+    let m = _mm512_castsi512_ps(m);
+    let small = _mm512_cmp_ps_mask::<_CMP_LT_OQ>(m, splat(std::f32::consts::FRAC_1_SQRT_2));
+    let e = _mm512_cvtepi32_ps(_mm512_mask_sub_epi32(e, small, e, splat_int(1)));
+    let f = _mm512_sub_ps(_mm512_mask_add_ps(m, small, m, m), splat(1.0));
+    let z = _mm512_mul_ps(f, f);
+    let mut p = splat(LN_COEFFICIENTS[0]);
+    for &coefficient in &LN_COEFFICIENTS[1..] {
+        p = _mm512_add_ps(_mm512_mul_ps(p, f), splat(coefficient));
+    }
+    let y = _mm512_add_ps(
+        _mm512_mul_ps(_mm512_mul_ps(p, f), z),
+        _mm512_mul_ps(e, splat(LN_2_LOW)),
+    );
+    let y = _mm512_sub_ps(y, _mm512_mul_ps(splat(0.5), z));
+    let ln = _mm512_add_ps(_mm512_add_ps(f, y), _mm512_mul_ps(e, splat(LN_2_HIGH)));
+    let magnitude = _mm512_sqrt_ps(_mm512_mul_ps(splat(-2.0), ln));
+
+    let scaled = _mm512_mul_ps(splat(4.0), u2);
+    let rounded = _mm512_add_ps(scaled, splat(ROUND_MAGIC));
+    let quadrant = _mm512_castps_si512(rounded);
+    let x = _mm512_sub_ps(scaled, _mm512_sub_ps(rounded, splat(ROUND_MAGIC)));
+    let x = _mm512_mul_ps(x, splat(std::f32::consts::FRAC_PI_2));
+    let z = _mm512_mul_ps(x, x);
+    let mut sin_x = splat(SIN_COEFFICIENTS[0]);
+    for &coefficient in &SIN_COEFFICIENTS[1..] {
+        sin_x = _mm512_add_ps(_mm512_mul_ps(sin_x, z), splat(coefficient));
+    }
+    let sin_x = _mm512_add_ps(_mm512_mul_ps(_mm512_mul_ps(sin_x, z), x), x);
+    let mut cos_x = splat(COS_COEFFICIENTS[0]);
+    for &coefficient in &COS_COEFFICIENTS[1..] {
+        cos_x = _mm512_add_ps(_mm512_mul_ps(cos_x, z), splat(coefficient));
+    }
+    let cos_x = _mm512_mul_ps(_mm512_mul_ps(cos_x, z), z);
+    let cos_x = _mm512_add_ps(
+        _mm512_sub_ps(cos_x, _mm512_mul_ps(splat(0.5), z)),
+        splat(1.0),
+    );
+    let odd = _mm512_test_epi32_mask(quadrant, splat_int(1));
+    let sin = _mm512_mask_blend_ps(odd, sin_x, cos_x);
+    let cos = _mm512_mask_blend_ps(odd, cos_x, sin_x);
+    // Integer xors, as the float ones need AVX-512 DQ.
+    let sin_sign = _mm512_slli_epi32::<30>(_mm512_and_si512(quadrant, splat_int(2)));
+    let cos_sign = _mm512_add_epi32(quadrant, splat_int(1));
+    let cos_sign = _mm512_slli_epi32::<30>(_mm512_and_si512(cos_sign, splat_int(2)));
+    let sin = _mm512_castsi512_ps(_mm512_xor_si512(_mm512_castps_si512(sin), sin_sign));
+    let cos = _mm512_castsi512_ps(_mm512_xor_si512(_mm512_castps_si512(cos), cos_sign));
+    (_mm512_mul_ps(magnitude, cos), _mm512_mul_ps(magnitude, sin))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{box_muller_pair, Kernel};
+    use crate::model::reconstruct_noise;
+    use rand::distributions::{Distribution, Uniform};
+    use rand::SeedableRng;
+    use rand_xoshiro::Xoroshiro128Plus;
+    use std::time::Instant;
+
+    fn noise(seed: u64, len: usize) -> Vec<f32> {
+        let mut noise = vec![0.0; len];
+        reconstruct_noise(seed, 0, &mut noise);
+        noise
+    }
+
+    fn bits(values: &[f32]) -> Vec<u32> {
+        values.iter().map(|value| value.to_bits()).collect()
+    }
+
+    #[test]
+    fn kernels_match_the_scalar_loops() {
+        let x = noise(1, 1003);
+        let y = noise(2, 1003);
+        for kernel in Kernel::available() {
+            // Every remainder length after the vector lanes, and a long run.
+            for len in (0..=33).chain([1003]) {
+                let (x, y) = (&x[..len], &y[..len]);
+
+                let mut expected = x.to_vec();
+                for (target, source) in expected.iter_mut().zip(y) {
+                    *target += source * 0.37;
+                }
+                let mut target = x.to_vec();
+                kernel.add_scaled(&mut target, y, 0.37);
+                assert_eq!(bits(&target), bits(&expected), "{:?} add_scaled", kernel);
+
+                let mut target = y.to_vec();
+                kernel.scale_and_add(&mut target, x, 0.37);
+                assert_eq!(bits(&target), bits(&expected), "{:?} scale_and_add", kernel);
+
+                let mut out = vec![0.0; len];
+                kernel.add_scaled_into(&mut out, x, y, 0.37);
+                assert_eq!(bits(&out), bits(&expected), "{:?} add_scaled_into", kernel);
+            }
+        }
+        assert!(Kernel::available().contains(&Kernel::detect()));
+    }
+
+    /// Uniform values as the noise uses them, with the ends of the range and the quadrant edges.
+    fn uniforms(len: usize) -> Vec<f32> {
+        let mut rng = Xoroshiro128Plus::seed_from_u64(5);
+        let uniform = Uniform::new(f32::EPSILON, 1.0);
+        let edges = [
+            f32::EPSILON,
+            1.0 - f32::EPSILON / 2.0,
+            0.125,
+            0.25,
+            0.375,
+            0.5,
+            0.75,
+        ];
+        let mut values: Vec<f32> = edges
+            .iter()
+            .flat_map(|&u1| edges.map(|u2| [u1, u2]))
+            .flatten()
+            .collect();
+        values.extend((values.len()..len).map(|_| uniform.sample(&mut rng)));
+        values
+    }
+
+    #[test]
+    fn box_muller_kernels_match_the_scalar_reference() {
+        let uniforms = uniforms(10_001);
+        let mut expected = uniforms.clone();
+        for pair in expected.chunks_exact_mut(2) {
+            (pair[0], pair[1]) = box_muller_pair(pair[0], pair[1]);
+        }
+        for kernel in Kernel::available() {
+            // Every remainder length after the vector lanes, an odd value left over, and a long run.
+            for len in (0..=65).chain([10_001]) {
+                let mut values = uniforms[..len].to_vec();
+                kernel.box_muller(&mut values);
+                let mut expected = expected[..len].to_vec();
+                if len % 2 == 1 {
+                    expected[len - 1] = uniforms[len - 1];
+                }
+                assert_eq!(
+                    bits(&values),
+                    bits(&expected),
+                    "{:?} box_muller {}",
+                    kernel,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn box_muller_pair_is_accurate() {
+        for pair in uniforms(100_000).chunks_exact(2) {
+            let (u1, u2) = (pair[0] as f64, pair[1] as f64);
+            let magnitude = (-2.0 * u1.ln()).sqrt();
+            let angle = 2.0 * std::f64::consts::PI * u2;
+            let (z0, z1) = box_muller_pair(pair[0], pair[1]);
+            for (actual, exact) in [(z0, magnitude * angle.cos()), (z1, magnitude * angle.sin())] {
+                let error = (actual as f64 - exact).abs();
+                assert!(
+                    error <= 1e-6 * magnitude.max(1.0),
+                    "{:?}: {} against {}",
+                    pair,
+                    actual,
+                    exact
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with cargo test --release -- --ignored --nocapture"]
+    fn benchmark_kernels() {
+        const LEN: usize = 1_000_000;
+        const ROUNDS: u32 = 200;
+        let source = noise(3, LEN);
+        for kernel in Kernel::available() {
+            let mut target = noise(4, LEN);
+            let start = Instant::now();
+            for _ in 0..ROUNDS {
+                kernel.add_scaled(&mut target, &source, 1e-3);
+            }
+            let elapsed = start.elapsed();
+            println!(
+                "{:?} add_scaled: {:.3} ns per value",
+                kernel,
+                elapsed.as_nanos() as f64 / (LEN as f64 * ROUNDS as f64)
+            );
+        }
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with cargo test --release -- --ignored --nocapture"]
+    fn benchmark_box_muller() {
+        const LEN: usize = 1_000_000;
+        const ROUNDS: u32 = 50;
+        let uniforms = uniforms(LEN);
+        for kernel in Kernel::available() {
+            let mut elapsed = std::time::Duration::ZERO;
+            for _ in 0..ROUNDS {
+                let mut values = uniforms.clone();
+                let start = Instant::now();
+                kernel.box_muller(&mut values);
+                elapsed += start.elapsed();
+            }
+            println!(
+                "{:?} box_muller: {:.3} ns per value",
+                kernel,
+                elapsed.as_nanos() as f64 / (LEN as f64 * ROUNDS as f64)
+            );
+        }
+    }
+}
@@ -0,0 +1,751 @@
+use crate::common::ModelVersion;
+use crate::fitness_shaping::FitnessShaping;
+use crate::optimizer::{OptimizerConfig, OptimizerKind, WeightDecay};
+use crate::staleness::{StalenessPolicy, StalenessWeighting};
+use serde::de::{self, DeserializeOwned, IntoDeserializer};
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Every setting, as its `section.key` name in the config file, its command line flag and what it
+/// controls. The same names are used for both so a flag always overrides the matching entry.
+#[rustfmt::skip]
+const SETTINGS: &[(&str, &str, &str)] = &[
+    ("listen.tcp", "--listen-tcp", "FramedTcp listen address, empty to disable"),
+    ("listen.udp", "--listen-udp", "UDP listen address, empty to disable"),
+    ("listen.ws", "--listen-ws", "WebSocket listen address, empty to disable"),
+    ("model.parameter_count", "--parameter-count", "number of model parameters"),
+    ("model.init_policy", "--init-policy", ".npy or raw f32 policy to start from"),
+    ("training.learning_rate", "--learning-rate", "step size of each update"),
+    ("optimizer.kind", "--optimizer", "sgd or adam"),
+    ("optimizer.momentum", "--momentum", "SGD momentum"),
+    ("optimizer.beta1", "--adam-beta1", "Adam first moment decay"),
+    ("optimizer.beta2", "--adam-beta2", "Adam second moment decay"),
+    ("optimizer.epsilon", "--adam-epsilon", "Adam denominator offset"),
+    ("optimizer.weight_decay", "--weight-decay", "weight decay, 0 to disable"),
+    ("optimizer.decoupled_weight_decay", "--decoupled-weight-decay", "true to decay apart from the gradient, false for L2"),
+    ("training.noise_std_dev", "--noise-std-dev", "standard deviation of the perturbations"),
+    ("training.population_size", "--population-size", "episodes per model update"),
+    ("training.mode", "--training-mode", "asynchronous, or synchronous to wait for each generation"),
+    ("training.generation_timeout_ms", "--generation-timeout-ms", "time a synchronous generation waits for its episodes"),
+    ("training.fitness_shaping", "--fitness-shaping", "centered_ranks, z_score, nes_utility or none"),
+    ("training.noise_table_size", "--noise-table-size", "values in the shared noise table"),
+    ("training.seed", "--seed", "seed for the learner's random number generator"),
+    ("training.maximum_model_age", "--maximum-model-age", "versions a transfer or episode may fall behind"),
+    ("training.staleness_weighting", "--staleness-weighting", "uniform, linear or exponential weighting of older episodes"),
+    ("training.staleness_decay", "--staleness-decay", "weight kept per version behind with exponential weighting"),
+    ("workers.initialisation_timeout_ms", "--initialisation-timeout-ms", "time a new worker has to initialise"),
+    ("workers.work_unit_size", "--work-unit-size", "perturbations assigned to a worker at a time, 0 lets workers choose"),
+    ("workers.work_unit_timeout_ms", "--work-unit-timeout-ms", "time a worker has to finish a unit before it is reassigned"),
+    ("checkpoint.directory", "--checkpoint-dir", "directory checkpoints are written to"),
+    ("checkpoint.interval", "--checkpoint-interval", "model versions between checkpoints"),
+    ("checkpoint.retained", "--checkpoints-retained", "number of checkpoints kept"),
+    ("checkpoint.resume", "--resume", "checkpoint file or directory to resume from"),
+];
+
+const DEFAULT_PARAMETER_COUNT: usize = 1_000_000;
+
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Settings for the learner daemon, read from a TOML config file and the command line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LearnerConfig {
+    pub tcp_listen_address: Option<String>,
+    pub udp_listen_address: Option<String>,
+    pub ws_listen_address: Option<String>,
+    /// Set explicitly, a loaded policy or checkpoint must have this many parameters.
+    pub parameter_count: Option<usize>,
+    pub init_policy: Option<PathBuf>,
+    pub optimizer: OptimizerKind,
+    pub learning_rate: f32,
+    pub momentum: f32,
+    pub adam_beta1: f32,
+    pub adam_beta2: f32,
+    pub adam_epsilon: f32,
+    pub weight_decay: f32,
+    pub decoupled_weight_decay: bool,
+    pub noise_std_dev: f32,
+    pub population_size: usize,
+    pub training_mode: TrainingMode,
+    /// How long a synchronous generation waits for `population_size` episodes before updating
+    /// with the ones it has.
+    pub generation_timeout: Duration,
+    /// Applied to the rewards of each batch before they weight the noise.
+    pub fitness_shaping: FitnessShaping,
+    pub noise_table_size: usize,
+    pub seed: u64,
+    /// Transfers and episodes more than this many versions behind the latest model are dropped.
+    pub maximum_model_age: ModelVersion,
+    pub staleness_weighting: StalenessKind,
+    pub staleness_decay: f32,
+    pub worker_initialisation_timeout: Duration,
+    /// Perturbations the learner assigns to a worker at a time, or 0 to let workers draw their
+    /// own seeds.
+    pub work_unit_size: usize,
+    /// How long a worker has to return the episodes of a unit of work before the perturbations it
+    /// has not finished are assigned to other workers.
+    pub work_unit_timeout: Duration,
+    pub checkpoint_directory: PathBuf,
+    pub checkpoint_interval: ModelVersion,
+    pub checkpoints_retained: usize,
+    pub resume: Option<PathBuf>,
+}
+
+impl Default for LearnerConfig {
+    fn default() -> LearnerConfig {
+        LearnerConfig {
+            tcp_listen_address: Some("0.0.0.0:3042".to_string()),
+            udp_listen_address: Some("0.0.0.0:3043".to_string()),
+            ws_listen_address: Some("0.0.0.0:3044".to_string()),
+            parameter_count: None,
+            init_policy: None,
+            optimizer: OptimizerKind::Sgd,
+            learning_rate: 0.01,
+            momentum: 0.0,
+            adam_beta1: 0.9,
+            adam_beta2: 0.999,
+            adam_epsilon: 1e-8,
+            weight_decay: 0.0,
+            decoupled_weight_decay: false,
+            noise_std_dev: 0.02,
+            population_size: 100,
+            training_mode: TrainingMode::Asynchronous,
+            generation_timeout: Duration::from_millis(60_000),
+            fitness_shaping: FitnessShaping::CenteredRanks,
+            noise_table_size: 25_000_000,
+            seed: 0x5EED_7AB1E,
+            maximum_model_age: 10,
+            staleness_weighting: StalenessKind::Linear,
+            staleness_decay: 0.5,
+            worker_initialisation_timeout: Duration::from_millis(3000),
+            work_unit_size: 10,
+            work_unit_timeout: Duration::from_millis(120_000),
+            checkpoint_directory: PathBuf::from("checkpoints"),
+            checkpoint_interval: 10,
+            checkpoints_retained: 5,
+            resume: None,
+        }
+    }
+}
+
+/// How the learner paces its updates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrainingMode {
+    /// Updates whenever a batch is full, from episodes of any recent version.
+    Asynchronous,
+    /// Publishes a version and waits for a full batch of episodes generated with it, or for the
+    /// generation timeout, before updating. Runs are reproducible given the same returns.
+    Synchronous,
+}
+
+/// The choice of `StalenessWeighting`, whose exponential decay is a separate setting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StalenessKind {
+    Uniform,
+    Linear,
+    Exponential,
+}
+
+/// The config file, where every setting is optional and those left out keep their defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    listen: ListenTable,
+    model: ModelTable,
+    training: TrainingTable,
+    optimizer: OptimizerTable,
+    workers: WorkersTable,
+    checkpoint: CheckpointTable,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ListenTable {
+    tcp: Option<String>,
+    udp: Option<String>,
+    ws: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ModelTable {
+    parameter_count: Option<usize>,
+    init_policy: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TrainingTable {
+    learning_rate: Option<f32>,
+    noise_std_dev: Option<f32>,
+    population_size: Option<usize>,
+    mode: Option<TrainingMode>,
+    generation_timeout_ms: Option<u64>,
+    fitness_shaping: Option<FitnessShaping>,
+    noise_table_size: Option<usize>,
+    seed: Option<u64>,
+    maximum_model_age: Option<ModelVersion>,
+    staleness_weighting: Option<StalenessKind>,
+    staleness_decay: Option<f32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct OptimizerTable {
+    kind: Option<OptimizerKind>,
+    momentum: Option<f32>,
+    beta1: Option<f32>,
+    beta2: Option<f32>,
+    epsilon: Option<f32>,
+    weight_decay: Option<f32>,
+    decoupled_weight_decay: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct WorkersTable {
+    initialisation_timeout_ms: Option<u64>,
+    work_unit_size: Option<usize>,
+    work_unit_timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CheckpointTable {
+    directory: Option<String>,
+    interval: Option<ModelVersion>,
+    retained: Option<usize>,
+    resume: Option<String>,
+}
+
+impl ConfigFile {
+    /// Overwrites the settings of `config` that the file sets.
+    fn apply(self, config: &mut LearnerConfig) {
+        let ConfigFile {
+            listen,
+            model,
+            training,
+            optimizer,
+            workers,
+            checkpoint,
+        } = self;
+        update(&mut config.tcp_listen_address, listen.tcp.map(optional));
+        update(&mut config.udp_listen_address, listen.udp.map(optional));
+        update(&mut config.ws_listen_address, listen.ws.map(optional));
+        update(&mut config.parameter_count, model.parameter_count.map(Some));
+        update(
+            &mut config.init_policy,
+            model.init_policy.map(optional_path),
+        );
+        update(&mut config.learning_rate, training.learning_rate);
+        update(&mut config.noise_std_dev, training.noise_std_dev);
+        update(&mut config.population_size, training.population_size);
+        update(&mut config.training_mode, training.mode);
+        update(
+            &mut config.generation_timeout,
+            training.generation_timeout_ms.map(Duration::from_millis),
+        );
+        update(&mut config.fitness_shaping, training.fitness_shaping);
+        update(&mut config.noise_table_size, training.noise_table_size);
+        update(&mut config.seed, training.seed);
+        update(&mut config.maximum_model_age, training.maximum_model_age);
+        update(
+            &mut config.staleness_weighting,
+            training.staleness_weighting,
+        );
+        update(&mut config.staleness_decay, training.staleness_decay);
+        update(&mut config.optimizer, optimizer.kind);
+        update(&mut config.momentum, optimizer.momentum);
+        update(&mut config.adam_beta1, optimizer.beta1);
+        update(&mut config.adam_beta2, optimizer.beta2);
+        update(&mut config.adam_epsilon, optimizer.epsilon);
+        update(&mut config.weight_decay, optimizer.weight_decay);
+        update(
+            &mut config.decoupled_weight_decay,
+            optimizer.decoupled_weight_decay,
+        );
+        update(
+            &mut config.worker_initialisation_timeout,
+            workers.initialisation_timeout_ms.map(Duration::from_millis),
+        );
+        update(&mut config.work_unit_size, workers.work_unit_size);
+        update(
+            &mut config.work_unit_timeout,
+            workers.work_unit_timeout_ms.map(Duration::from_millis),
+        );
+        update(
+            &mut config.checkpoint_directory,
+            checkpoint.directory.map(PathBuf::from),
+        );
+        update(&mut config.checkpoint_interval, checkpoint.interval);
+        update(&mut config.checkpoints_retained, checkpoint.retained);
+        update(&mut config.resume, checkpoint.resume.map(optional_path));
+    }
+}
+
+fn update<T>(setting: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *setting = value;
+    }
+}
+
+/// An empty string disables the setting.
+fn optional(value: String) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+fn optional_path(value: String) -> Option<PathBuf> {
+    optional(value).map(PathBuf::from)
+}
+
+fn parse_integer<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .replace('_', "")
+        .parse()
+        .map_err(|_| format!("expected a non-negative integer, found {}", value))
+}
+
+fn parse_number(value: &str) -> Result<f32, String> {
+    value
+        .parse()
+        .map_err(|_| format!("expected a number, found {}", value))
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    value
+        .parse()
+        .map_err(|_| format!("expected true or false, found {}", value))
+}
+
+/// One of the names an enum has in the config file, such as `synchronous` or `z_score`.
+fn parse_choice<T: DeserializeOwned>(value: &str) -> Result<T, String> {
+    T::deserialize(value.into_deserializer()).map_err(|error: de::value::Error| error.to_string())
+}
+
+impl LearnerConfig {
+    /// Builds the configuration from command line arguments, excluding the program name.
+    /// `--config <file>` is read first wherever it appears, then the other flags override it.
+    pub fn from_args<I>(args: I) -> Result<LearnerConfig, ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut config_path = None;
+        let mut overrides = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| ConfigError(format!("{} needs a value", flag)))?;
+            if flag == "--config" {
+                config_path = Some(PathBuf::from(value));
+                continue;
+            }
+            let name = SETTINGS
+                .iter()
+                .find(|(_, setting_flag, _)| *setting_flag == flag)
+                .map(|(name, _, _)| *name)
+                .ok_or_else(|| ConfigError(format!("Unknown option {}", flag)))?;
+            overrides.push((name, flag, value));
+        }
+
+        let mut config = match config_path {
+            Some(path) => LearnerConfig::from_file(&path)?,
+            None => LearnerConfig::default(),
+        };
+        for (name, flag, value) in overrides {
+            config
+                .set(name, &value)
+                .map_err(|error| ConfigError(format!("{}: {}", flag, error)))?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<LearnerConfig, ConfigError> {
+        let text = fs::read_to_string(path)
+            .map_err(|error| ConfigError(format!("{}: {}", path.display(), error)))?;
+        LearnerConfig::from_toml(&text)
+            .map_err(|error| ConfigError(format!("{}: {}", path.display(), error)))
+    }
+
+    /// Reads the settings present in a TOML document over the defaults. Errors give the line and
+    /// column they refer to.
+    pub fn from_toml(text: &str) -> Result<LearnerConfig, ConfigError> {
+        let file: ConfigFile =
+            toml::from_str(text).map_err(|error| ConfigError(error.to_string()))?;
+        let mut config = LearnerConfig::default();
+        file.apply(&mut config);
+        Ok(config)
+    }
+
+    /// Usage text listing every option.
+    pub fn usage() -> String {
+        let mut usage = String::from(
+            "Usage: network_daemon [--config <file>] [options]\n\n\
+             Options override the matching `section.key` in the TOML config file:\n",
+        );
+        usage.push_str(&format!(
+            "  {:<30} {:<34} {}\n",
+            "--config", "", "TOML config file"
+        ));
+        for (name, flag, description) in SETTINGS {
+            usage.push_str(&format!("  {:<30} {:<34} {}\n", flag, name, description));
+        }
+        usage
+    }
+
+    /// Sets the setting called `name` in the config file from the text of a command line argument.
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "listen.tcp" => self.tcp_listen_address = optional(value.to_string()),
+            "listen.udp" => self.udp_listen_address = optional(value.to_string()),
+            "listen.ws" => self.ws_listen_address = optional(value.to_string()),
+            "model.parameter_count" => self.parameter_count = Some(parse_integer(value)?),
+            "model.init_policy" => self.init_policy = optional_path(value.to_string()),
+            "training.learning_rate" => self.learning_rate = parse_number(value)?,
+            "optimizer.kind" => self.optimizer = parse_choice(value)?,
+            "optimizer.momentum" => self.momentum = parse_number(value)?,
+            "optimizer.beta1" => self.adam_beta1 = parse_number(value)?,
+            "optimizer.beta2" => self.adam_beta2 = parse_number(value)?,
+            "optimizer.epsilon" => self.adam_epsilon = parse_number(value)?,
+            "optimizer.weight_decay" => self.weight_decay = parse_number(value)?,
+            "optimizer.decoupled_weight_decay" => self.decoupled_weight_decay = parse_bool(value)?,
+            "training.noise_std_dev" => self.noise_std_dev = parse_number(value)?,
+            "training.population_size" => self.population_size = parse_integer(value)?,
+            "training.mode" => self.training_mode = parse_choice(value)?,
+            "training.generation_timeout_ms" => {
+                self.generation_timeout = Duration::from_millis(parse_integer(value)?)
+            }
+            "training.fitness_shaping" => self.fitness_shaping = parse_choice(value)?,
+            "training.noise_table_size" => self.noise_table_size = parse_integer(value)?,
+            "training.seed" => self.seed = parse_integer(value)?,
+            "training.maximum_model_age" => self.maximum_model_age = parse_integer(value)?,
+            "training.staleness_weighting" => self.staleness_weighting = parse_choice(value)?,
+            "training.staleness_decay" => self.staleness_decay = parse_number(value)?,
+            "workers.initialisation_timeout_ms" => {
+                self.worker_initialisation_timeout = Duration::from_millis(parse_integer(value)?)
+            }
+            "workers.work_unit_size" => self.work_unit_size = parse_integer(value)?,
+            "workers.work_unit_timeout_ms" => {
+                self.work_unit_timeout = Duration::from_millis(parse_integer(value)?)
+            }
+            "checkpoint.directory" => self.checkpoint_directory = PathBuf::from(value),
+            "checkpoint.interval" => self.checkpoint_interval = parse_integer(value)?,
+            "checkpoint.retained" => self.checkpoints_retained = parse_integer(value)?,
+            "checkpoint.resume" => self.resume = optional_path(value.to_string()),
+            _ => return Err("unknown setting".to_string()),
+        }
+        Ok(())
+    }
+
+    /// The model size to use when starting without an initial policy or checkpoint.
+    pub fn default_parameter_count(&self) -> usize {
+        self.parameter_count.unwrap_or(DEFAULT_PARAMETER_COUNT)
+    }
+
+    pub fn staleness_policy(&self) -> StalenessPolicy {
+        StalenessPolicy {
+            weighting: match self.staleness_weighting {
+                StalenessKind::Uniform => StalenessWeighting::Uniform,
+                StalenessKind::Linear => StalenessWeighting::Linear,
+                StalenessKind::Exponential => StalenessWeighting::Exponential(self.staleness_decay),
+            },
+            maximum_age: self.maximum_model_age,
+        }
+    }
+
+    pub fn optimizer_config(&self) -> OptimizerConfig {
+        let weight_decay = if self.weight_decay == 0.0 {
+            WeightDecay::None
+        } else if self.decoupled_weight_decay {
+            WeightDecay::Decoupled(self.weight_decay)
+        } else {
+            WeightDecay::L2(self.weight_decay)
+        };
+        OptimizerConfig {
+            kind: self.optimizer,
+            learning_rate: self.learning_rate,
+            momentum: self.momentum,
+            beta1: self.adam_beta1,
+            beta2: self.adam_beta2,
+            epsilon: self.adam_epsilon,
+            weight_decay,
+        }
+    }
+
+    /// Checks the settings make sense together, reporting every problem at once.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        let listen_addresses = [
+            ("listen.tcp", &self.tcp_listen_address),
+            ("listen.udp", &self.udp_listen_address),
+            ("listen.ws", &self.ws_listen_address),
+        ];
+        if listen_addresses
+            .iter()
+            .all(|(_, address)| address.is_none())
+        {
+            problems.push("at least one listen address is needed".to_string());
+        }
+        for (name, address) in listen_addresses {
+            if let Some(address) = address {
+                if address.to_socket_addrs().is_err() {
+                    problems.push(format!("{} {} is not a valid address", name, address));
+                }
+            }
+        }
+        if self.tcp_listen_address.is_some() && self.tcp_listen_address == self.ws_listen_address {
+            problems.push("listen.tcp and listen.ws cannot share an address".to_string());
+        }
+
+        if self.parameter_count == Some(0) {
+            problems.push("model.parameter_count must be positive".to_string());
+        }
+        if self.init_policy.is_some() && self.resume.is_some() {
+            problems.push("model.init_policy and checkpoint.resume cannot both be set".to_string());
+        }
+        if !(self.learning_rate.is_finite() && self.learning_rate > 0.0) {
+            problems.push("training.learning_rate must be a positive number".to_string());
+        }
+        let fractions = [
+            ("optimizer.momentum", self.momentum),
+            ("optimizer.beta1", self.adam_beta1),
+            ("optimizer.beta2", self.adam_beta2),
+        ];
+        for (name, fraction) in fractions {
+            if !(0.0..1.0).contains(&fraction) {
+                problems.push(format!("{} must be at least 0 and less than 1", name));
+            }
+        }
+        if !(self.staleness_decay > 0.0 && self.staleness_decay <= 1.0) {
+            problems.push("training.staleness_decay must be more than 0 and at most 1".to_string());
+        }
+        if !(self.adam_epsilon.is_finite() && self.adam_epsilon > 0.0) {
+            problems.push("optimizer.epsilon must be a positive number".to_string());
+        }
+        if !(self.weight_decay.is_finite() && self.weight_decay >= 0.0) {
+            problems.push("optimizer.weight_decay must not be negative".to_string());
+        }
+        if !(self.noise_std_dev.is_finite() && self.noise_std_dev > 0.0) {
+            problems.push("training.noise_std_dev must be a positive number".to_string());
+        }
+        if self.population_size == 0 {
+            problems.push("training.population_size must be positive".to_string());
+        }
+        if self.generation_timeout.is_zero() {
+            problems.push("training.generation_timeout_ms must be positive".to_string());
+        }
+        if self.noise_table_size == 0 {
+            problems.push("training.noise_table_size must be positive".to_string());
+        }
+        if self.worker_initialisation_timeout.is_zero() {
+            problems.push("workers.initialisation_timeout_ms must be positive".to_string());
+        }
+        if self.work_unit_timeout.is_zero() {
+            problems.push("workers.work_unit_timeout_ms must be positive".to_string());
+        }
+        if self.checkpoint_interval == 0 {
+            problems.push("checkpoint.interval must be positive".to_string());
+        }
+        if self.checkpoints_retained == 0 {
+            problems.push("checkpoint.retained must be positive".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError(format!(
+                "Invalid configuration:\n  {}",
+                problems.join("\n  ")
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LearnerConfig, TrainingMode};
+    use crate::fitness_shaping::FitnessShaping;
+    use crate::optimizer::{OptimizerKind, WeightDecay};
+    use crate::staleness::StalenessWeighting;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn reads_config_file() {
+        let config = LearnerConfig::from_toml(
+            r#"
+            # Experiment settings
+            [listen]
+            tcp = "127.0.0.1:4042" # comments can follow values
+            udp = ""
+
+            [model]
+            init_policy = 'policies/start#1.npy'
+
+            [training]
+            learning_rate = 0.05
+            noise_std_dev = 1e-2
+            population_size = 1_000
+            mode = "synchronous"
+            generation_timeout_ms = 20_000
+            fitness_shaping = "nes_utility"
+            staleness_weighting = "exponential"
+            staleness_decay = 0.75
+            seed = 42
+
+            [optimizer]
+            kind = "adam"
+            weight_decay = 0.001
+            decoupled_weight_decay = true
+
+            [workers]
+            initialisation_timeout_ms = 500
+            work_unit_size = 0
+            work_unit_timeout_ms = 30_000
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.tcp_listen_address.as_deref(), Some("127.0.0.1:4042"));
+        assert_eq!(config.udp_listen_address, None);
+        assert_eq!(config.ws_listen_address.as_deref(), Some("0.0.0.0:3044"));
+        assert_eq!(
+            config.init_policy,
+            Some(PathBuf::from("policies/start#1.npy"))
+        );
+        assert_eq!(config.learning_rate, 0.05);
+        assert_eq!(config.noise_std_dev, 0.01);
+        assert_eq!(config.population_size, 1000);
+        assert_eq!(config.training_mode, TrainingMode::Synchronous);
+        assert_eq!(config.generation_timeout, Duration::from_millis(20_000));
+        assert_eq!(config.fitness_shaping, FitnessShaping::NesUtility);
+        assert_eq!(
+            config.staleness_policy().weighting,
+            StalenessWeighting::Exponential(0.75)
+        );
+        assert_eq!(config.seed, 42);
+        let optimizer = config.optimizer_config();
+        assert_eq!(optimizer.kind, OptimizerKind::Adam);
+        assert_eq!(optimizer.weight_decay, WeightDecay::Decoupled(0.001));
+        assert_eq!(
+            config.worker_initialisation_timeout,
+            Duration::from_millis(500)
+        );
+        assert_eq!(config.work_unit_size, 0);
+        assert_eq!(config.work_unit_timeout, Duration::from_millis(30_000));
+        assert_eq!(
+            config.checkpoint_interval, 10,
+            "Unset values keep their defaults."
+        );
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn reports_config_errors() {
+        let cases = [
+            (
+                "[training]\nlearning_rat = 0.1",
+                "unknown field `learning_rat`",
+            ),
+            ("[training]\nseed = -1", "invalid value: integer `-1`"),
+            (
+                "[training]\nmode = \"lockstep\"",
+                "unknown variant `lockstep`",
+            ),
+            (
+                "[listen]\ntcp = 3042",
+                "invalid type: integer `3042`, expected a string",
+            ),
+            ("[listen]\ntcp = \"open", "invalid basic string"),
+            ("[checkpoint]\ninterval = 1\ninterval = 2", "duplicate key"),
+        ];
+        for (text, problem) in cases {
+            let error = LearnerConfig::from_toml(text).unwrap_err().to_string();
+            let line = text.lines().count();
+            assert!(
+                error.contains(&format!("line {},", line)) && error.contains(problem),
+                "{:?} gave {}",
+                text,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn arguments_override_and_validate() {
+        let config = LearnerConfig::from_args(args(&[
+            "--population-size",
+            "20",
+            "--listen-ws",
+            "",
+            "--resume",
+            "checkpoints",
+        ]))
+        .unwrap();
+        assert_eq!(config.population_size, 20);
+        assert_eq!(config.ws_listen_address, None);
+        assert_eq!(config.resume, Some(PathBuf::from("checkpoints")));
+
+        let config =
+            LearnerConfig::from_args(args(&["--fitness-shaping", "z_score", "--seed", "1_000"]))
+                .unwrap();
+        assert_eq!(config.fitness_shaping, FitnessShaping::ZScore);
+        assert_eq!(config.seed, 1000);
+        let error = LearnerConfig::from_args(args(&["--training-mode", "lockstep"]))
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("--training-mode: unknown variant `lockstep`"));
+
+        assert!(LearnerConfig::from_args(args(&["--learning-rate"])).is_err());
+        assert!(LearnerConfig::from_args(args(&["--unknown", "1"])).is_err());
+        let error = LearnerConfig::from_args(args(&[
+            "--learning-rate",
+            "0",
+            "--init-policy",
+            "policy.npy",
+            "--resume",
+            "checkpoints",
+        ]))
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("training.learning_rate must be a positive number"));
+        assert!(
+            error.contains("cannot both be set"),
+            "Every problem should be reported."
+        );
+    }
+
+    #[test]
+    fn example_config_is_the_default() {
+        let example =
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("network_daemon.example.toml");
+        assert_eq!(
+            LearnerConfig::from_file(&example).unwrap(),
+            LearnerConfig::default()
+        );
+    }
+}
@@ -1,38 +1,213 @@
+pub mod checkpoint;
 mod collect_slice;
 pub mod common;
+pub mod fitness_shaping;
+pub mod gradient;
+pub mod kernels;
+pub mod learner_config;
 pub mod model;
-mod noise;
+pub mod model_history;
+pub mod noise;
+pub mod noise_table;
+pub mod optimizer;
+pub mod staleness;
+pub mod work_schedule;
 mod worker;
 
-use noise::par_fill_noise_standard;
-use numpy::PyArray1;
-use pyo3::exceptions::{PyIOError, PyValueError};
+use common::ModelVersion;
+use model::SamplingMode;
+use numpy::{PyArray1, PyReadonlyArray1};
+use pyo3::exceptions::{PyIOError, PyRuntimeError, PyTimeoutError, PyValueError};
 use pyo3::prelude::*;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 use message_io::network::Transport;
 
-use rand::SeedableRng;
-use rand_xoshiro::Xoroshiro128Plus;
+use crate::worker::{RetryPolicy, Worker};
 
-use crate::worker::Worker;
+/// How often a blocking wait wakes up to let Python handle signals such as KeyboardInterrupt.
+const SIGNAL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// pyo3 0.16 checks a `cfg(addr_of)` that newer compilers do not know about.
+#[allow(unexpected_cfgs)]
+mod exceptions {
+    use pyo3::create_exception;
+    use pyo3::exceptions::PyIOError;
+
+    create_exception!(fdlib, ProtocolError, PyIOError);
+}
+use exceptions::ProtocolError;
+
+impl From<worker::ProtocolError> for PyErr {
+    fn from(error: worker::ProtocolError) -> PyErr {
+        ProtocolError::new_err(error.to_string())
+    }
+}
 
 #[pymethods]
 impl Worker {
-    fn get_parameters(&mut self, py: Python) -> PyResult<PyObject> {
+    /// Returns a fresh perturbation of the latest policy, or None if no policy has arrived yet or
+    /// the perturbations assigned by the learner are used up until it assigns more.
+    /// In mirrored mode every other call returns the negative of the previous perturbation.
+    /// Passing a writeable, contiguous float32 array as `out` fills it in place and returns it,
+    /// which avoids allocating and copying a new array on every call.
+    /// Raises ProtocolError if the learner sent something unusable since the last call, the
+    /// worker will already be recovering from it.
+    #[args(out = "None")]
+    fn get_parameters(&mut self, py: Python, out: Option<&PyArray1<f32>>) -> PyResult<PyObject> {
+        self.process_signals();
+        if let Some(error) = self.protocol_error.take() {
+            return Err(error.into());
+        }
+        match out {
+            Some(out) => self.get_parameters_into(py, out),
+            None => match self.perturb() {
+                Some(buffer) => Ok(PyArray1::from_slice(py, buffer).as_ref().to_object(py)),
+                None => Ok(py.None()),
+            },
+        }
+    }
+
+    /// Like `get_parameters`, but blocks until there is a perturbation to hand out. Raises
+    /// TimeoutError if `timeout` seconds pass first.
+    #[args(timeout = "None", out = "None")]
+    fn wait_for_parameters(
+        &mut self,
+        py: Python,
+        timeout: Option<f64>,
+        out: Option<&PyArray1<f32>>,
+    ) -> PyResult<PyObject> {
+        wait_for(self, py, timeout, Worker::can_perturb)?;
+        self.get_parameters(py, out)
+    }
+
+    /// Blocks until the worker has a policy of at least `min_version` and returns its version.
+    /// Raises TimeoutError if `timeout` seconds pass first.
+    #[args(timeout = "None")]
+    fn wait_for_model_version(
+        &mut self,
+        py: Python,
+        min_version: ModelVersion,
+        timeout: Option<f64>,
+    ) -> PyResult<ModelVersion> {
+        wait_for(self, py, timeout, move |worker| {
+            worker.has_policy()
+                && worker
+                    .model_version
+                    .is_some_and(|version| version >= min_version)
+        })?;
+        Ok(self.model_version.unwrap_or_default())
+    }
+
+    /// One of "connecting", "initialising", "ready" or "disconnected".
+    fn connection_state(&mut self) -> &'static str {
         self.process_signals();
-        match self.buffer {
-            Some(ref mut buffer) => {
-                par_fill_noise_standard(Xoroshiro128Plus::seed_from_u64(0x1337), buffer);
-                Ok(PyArray1::from_slice(py, buffer).as_ref().to_object(py))
-            }
-            None => Ok(py.None()),
+        self.connection_state.as_str()
+    }
+
+    /// Reports the reward earned by the parameters from the last call to `get_parameters`.
+    fn send_returns(&mut self, reward: f32) -> PyResult<()> {
+        if self.queue_episode(reward) {
+            Ok(())
+        } else {
+            Err(PyRuntimeError::new_err(
+                "No parameters are awaiting a reward, call get_parameters first.",
+            ))
         }
     }
 }
 
-/// Formats the sum of two numbers as string.
-#[pyfunction]
-fn create_worker(connection_string: String) -> PyResult<Worker> {
+impl Worker {
+    fn get_parameters_into(&mut self, py: Python, out: &PyArray1<f32>) -> PyResult<PyObject> {
+        if !out
+            .getattr("flags")?
+            .getattr("writeable")?
+            .extract::<bool>()?
+        {
+            return Err(PyValueError::new_err("out must be a writeable array."));
+        }
+        if !self.can_perturb() {
+            return Ok(py.None());
+        }
+        let expected_len = self.policy.as_ref().map_or(0, Vec::len);
+        // Safety: the GIL is held and no other Rust reference to the array exists for the
+        // duration of the fill.
+        let buffer = unsafe { out.as_slice_mut() }
+            .map_err(|_| PyValueError::new_err("out must be a contiguous array."))?;
+        if buffer.len() != expected_len {
+            return Err(PyValueError::new_err(format!(
+                "out has {} elements, expected {}.",
+                buffer.len(),
+                expected_len
+            )));
+        }
+        if !self.perturb_into(buffer) {
+            return Ok(py.None());
+        }
+        Ok(out.to_object(py))
+    }
+}
+
+/// Blocks with the GIL released until `ready` holds for the worker, raising any protocol error
+/// that arrives while waiting.
+fn wait_for<F>(worker: &mut Worker, py: Python, timeout: Option<f64>, ready: F) -> PyResult<()>
+where
+    F: Fn(&Worker) -> bool + Copy + Send + Sync,
+{
+    let deadline = match timeout {
+        Some(timeout) if timeout.is_finite() && timeout >= 0.0 => {
+            Some(Instant::now() + Duration::from_secs_f64(timeout))
+        }
+        Some(timeout) => {
+            return Err(PyValueError::new_err(format!(
+                "Invalid timeout: {}, expected a non-negative number of seconds.",
+                timeout
+            )))
+        }
+        None => None,
+    };
+
+    loop {
+        let check_deadline = Instant::now() + SIGNAL_CHECK_INTERVAL;
+        let wait_deadline =
+            deadline.map_or(check_deadline, |deadline| deadline.min(check_deadline));
+        if py.allow_threads(|| worker.wait_until(wait_deadline, ready)) {
+            break;
+        }
+        py.check_signals()?;
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(PyTimeoutError::new_err(
+                "Timed out waiting for the learner to send a model.",
+            ));
+        }
+    }
+
+    match worker.protocol_error.take() {
+        Some(error) => Err(error.into()),
+        None => Ok(()),
+    }
+}
+
+/// Connects a worker to the learner. `noise_std_dev` overrides the perturbation step size sent by
+/// the learner, `mirrored_sampling` hands out each noise vector as a `+noise`, `-noise` pair.
+/// Lost connections are retried with exponential backoff from `retry_initial_delay` up to
+/// `retry_max_delay` seconds, giving up after `max_retries` failures if it is set.
+#[pyfunction(
+    noise_std_dev = "None",
+    mirrored_sampling = "false",
+    retry_initial_delay = "0.5",
+    retry_max_delay = "30.0",
+    max_retries = "None"
+)]
+fn create_worker(
+    connection_string: String,
+    noise_std_dev: Option<f32>,
+    mirrored_sampling: bool,
+    retry_initial_delay: f64,
+    retry_max_delay: f64,
+    max_retries: Option<u32>,
+) -> PyResult<Worker> {
     // connection_string can start with tcp:// or ws://
     // parse the connection string
 
@@ -42,19 +217,66 @@ fn create_worker(connection_string: String) -> PyResult<Worker> {
         Some("wss") => (Transport::Ws, connection_string),
         _ => return Err(PyValueError::new_err(format!("Invalid connection string: {}, expected scheme://host:port where scheme is tcp, ws or wss.", connection_string))),
     };
-    match Worker::new(transport, addr) {
+    if let Some(noise_std_dev) = noise_std_dev {
+        if !(noise_std_dev.is_finite() && noise_std_dev > 0.0) {
+            return Err(PyValueError::new_err(format!(
+                "Invalid noise_std_dev: {}, expected a positive number.",
+                noise_std_dev
+            )));
+        }
+    }
+    if !(retry_initial_delay.is_finite()
+        && retry_initial_delay > 0.0
+        && retry_max_delay.is_finite()
+        && retry_max_delay >= retry_initial_delay)
+    {
+        return Err(PyValueError::new_err(format!(
+            "Invalid retry delays: {}, {}, expected 0 < retry_initial_delay <= retry_max_delay.",
+            retry_initial_delay, retry_max_delay
+        )));
+    }
+    let retry_policy = RetryPolicy {
+        initial_delay: Duration::from_secs_f64(retry_initial_delay),
+        max_delay: Duration::from_secs_f64(retry_max_delay),
+        max_retries,
+    };
+    let sampling_mode = if mirrored_sampling {
+        SamplingMode::Mirrored
+    } else {
+        SamplingMode::Independent
+    };
+    match Worker::new(transport, addr, noise_std_dev, sampling_mode, retry_policy) {
         Ok(worker) => Ok(worker),
         Err(err) => Err(PyIOError::new_err(format!("{}", err))),
     }
 }
 
+/// Writes a policy to `path`, as a NumPy `.npy` file if the path ends in `.npy` and as raw little
+/// endian f32 values otherwise.
+#[pyfunction]
+fn save_policy(path: PathBuf, policy: PyReadonlyArray1<f32>) -> PyResult<()> {
+    let policy: Vec<f32> = policy.as_array().iter().copied().collect();
+    model::save_policy(&path, &policy).map_err(|error| PyIOError::new_err(error.to_string()))
+}
+
+/// Reads a policy written by `save_policy`, NumPy or another tool, see `save_policy` for formats.
+#[pyfunction]
+fn load_policy(py: Python, path: PathBuf) -> PyResult<PyObject> {
+    let policy =
+        model::load_policy(&path).map_err(|error| PyIOError::new_err(error.to_string()))?;
+    Ok(PyArray1::from_vec(py, policy).as_ref().to_object(py))
+}
+
 /// A Python module implemented in Rust. The name of this function must match
 /// the `lib.name` setting in the `Cargo.toml`, else Python will not be able to
 /// import the module.
 #[pymodule]
-fn fdlib(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
+fn fdlib(py: Python<'_>, m: &PyModule) -> PyResult<()> {
     m.add_class::<Worker>()?;
+    m.add("ProtocolError", py.get_type::<ProtocolError>())?;
     m.add_function(wrap_pyfunction!(create_worker, m)?)?;
+    m.add_function(wrap_pyfunction!(save_policy, m)?)?;
+    m.add_function(wrap_pyfunction!(load_policy, m)?)?;
     // m.add_function(wrap_pyfunction!(get_buffer, m)?)?;
 
     Ok(())
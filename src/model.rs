@@ -1,4 +1,6 @@
-use crate::noise::par_fill_noise_standard;
+use crate::common::NoiseSign;
+use crate::kernels;
+use crate::noise::{par_fill_noise_standard, BlockJumpNoise, NoiseSource};
 use bincode::{deserialize, serialize, Result};
 use rand::{thread_rng, Rng, SeedableRng};
 use rand_xoshiro::Xoroshiro128Plus;
@@ -6,8 +8,12 @@ use rayon::{
     prelude::{IndexedParallelIterator, ParallelIterator},
     slice::{ParallelSlice, ParallelSliceMut},
 };
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::Path;
 
-const PAR_CHUNK_SIZE: usize = 100_000;
+pub(crate) const PAR_CHUNK_SIZE: usize = 100_000;
 
 pub fn serialize_parameters(parameters: &[f32]) -> Result<Vec<u8>> {
     serialize(parameters)
@@ -17,25 +23,279 @@ pub fn deserialize_parameters(parameters: &[u8]) -> Result<Vec<f32>> {
     deserialize(parameters)
 }
 
+/// How a worker chooses the noise for successive perturbations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SamplingMode {
+    /// Every perturbation uses a fresh seed.
+    Independent,
+    /// Every seed is used twice, first as `+noise` and then as `-noise` (antithetic sampling).
+    Mirrored,
+}
+
+/// Hands out the seed and sign of each perturbation according to a `SamplingMode`.
+///
+/// Seeds are drawn at random until the learner assigns some, after which only assigned seeds are
+/// handed out, in the order they were assigned.
+pub struct PerturbationSampler {
+    mode: SamplingMode,
+    mirror_seed: Option<u64>,
+    assigned_seeds: VecDeque<u64>,
+    learner_assigned: bool,
+}
+
+impl PerturbationSampler {
+    pub fn new(mode: SamplingMode) -> PerturbationSampler {
+        PerturbationSampler {
+            mode,
+            mirror_seed: None,
+            assigned_seeds: VecDeque::new(),
+            learner_assigned: false,
+        }
+    }
+
+    pub fn assign(&mut self, seeds: Vec<u64>) {
+        self.learner_assigned = true;
+        self.assigned_seeds.extend(seeds);
+    }
+
+    /// Forgets any assigned seeds and goes back to drawing its own.
+    pub fn reset(&mut self) {
+        self.mirror_seed = None;
+        self.assigned_seeds.clear();
+        self.learner_assigned = false;
+    }
+
+    /// True if the learner assigns the seeds and every one of them has been handed out.
+    pub fn is_waiting_for_seeds(&self) -> bool {
+        self.learner_assigned && self.mirror_seed.is_none() && self.assigned_seeds.is_empty()
+    }
+
+    /// Returns the seed and sign of the next perturbation, or None while waiting for seeds.
+    pub fn next_perturbation(&mut self) -> Option<(u64, NoiseSign)> {
+        if let Some(seed) = self.mirror_seed.take() {
+            return Some((seed, NoiseSign::Negative));
+        }
+        let seed = if self.learner_assigned {
+            self.assigned_seeds.pop_front()?
+        } else {
+            thread_rng().gen()
+        };
+        if self.mode == SamplingMode::Mirrored {
+            self.mirror_seed = Some(seed);
+        }
+        Some((seed, NoiseSign::Positive))
+    }
+}
+
 pub fn permute_parameters(policy: &[f32], buffer: &mut [f32], step_size: f32) -> u64 {
     let seed = thread_rng().gen();
+    permute_parameters_with_seed(policy, buffer, step_size, seed, NoiseSign::Positive);
+    seed
+}
+
+/// Fills `buffer` with `policy + step_size * sign * noise`, where the noise is drawn from `seed`.
+pub fn permute_parameters_with_seed(
+    policy: &[f32],
+    buffer: &mut [f32],
+    step_size: f32,
+    seed: u64,
+    sign: NoiseSign,
+) {
     let rng = Xoroshiro128Plus::seed_from_u64(seed);
     par_fill_noise_standard(rng, buffer);
 
+    let step_size = step_size * sign.as_f32();
     buffer
         .par_chunks_mut(PAR_CHUNK_SIZE)
         .zip(policy.par_chunks(PAR_CHUNK_SIZE))
         .for_each(|(param_chunk, policy_chunk)| {
-            for (param, policy) in param_chunk.iter_mut().zip(policy_chunk) {
-                *param = policy + *param * step_size;
-            }
+            kernels::scale_and_add(param_chunk, policy_chunk, step_size);
         });
+}
 
-    seed
+/// Fills `buffer` with `policy + step_size * sign * noise`, for noise taken from a noise table.
+pub fn permute_parameters_with_noise(
+    policy: &[f32],
+    noise: &[f32],
+    buffer: &mut [f32],
+    step_size: f32,
+    sign: NoiseSign,
+) {
+    let step_size = step_size * sign.as_f32();
+    buffer
+        .par_chunks_mut(PAR_CHUNK_SIZE)
+        .zip(policy.par_chunks(PAR_CHUNK_SIZE))
+        .zip(noise.par_chunks(PAR_CHUNK_SIZE))
+        .for_each(|((param_chunk, policy_chunk), noise_chunk)| {
+            kernels::add_scaled_into(param_chunk, policy_chunk, noise_chunk, step_size);
+        });
+}
+
+/// Regenerates the noise a worker used for a perturbation, starting `noise_offset` values into
+/// the standard normal stream produced by `seed`, without generating the values before it.
+pub fn reconstruct_noise(seed: u64, noise_offset: usize, buffer: &mut [f32]) {
+    BlockJumpNoise.fill_range(seed, noise_offset, buffer);
+}
+
+const NPY_MAGIC: &[u8; 6] = b"\x93NUMPY";
+const NPY_EXTENSION: &str = "npy";
+// Magic, version and the two byte header length of a version 1.0 file.
+const NPY_PREAMBLE_LEN: usize = 6 + 2 + 2;
+const NPY_ALIGNMENT: usize = 64;
+
+fn invalid_policy(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads a policy from `path`, as a NumPy `.npy` file if it has that extension and as headerless
+/// little endian f32 values otherwise.
+pub fn load_policy(path: &Path) -> io::Result<Vec<f32>> {
+    let data = fs::read(path)?;
+    let policy = if has_npy_extension(path) {
+        parse_npy(&data)
+    } else {
+        parse_raw_f32(&data)
+    };
+    policy.map_err(|error| invalid_policy(format!("{}: {}", path.display(), error)))
+}
+
+/// Writes a policy to `path` in the format `load_policy` would read it back from.
+pub fn save_policy(path: &Path, policy: &[f32]) -> io::Result<()> {
+    let data = if has_npy_extension(path) {
+        write_npy(policy)
+    } else {
+        write_raw_f32(policy)
+    };
+    fs::write(path, data)
+}
+
+fn has_npy_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case(NPY_EXTENSION))
+}
+
+/// Parses little endian f32 values with no header.
+pub fn parse_raw_f32(data: &[u8]) -> io::Result<Vec<f32>> {
+    if !data.len().is_multiple_of(4) {
+        return Err(invalid_policy(format!(
+            "{} bytes is not a whole number of f32 values",
+            data.len()
+        )));
+    }
+    Ok(data
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+        .collect())
+}
+
+pub fn write_raw_f32(policy: &[f32]) -> Vec<u8> {
+    policy
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+}
+
+/// Parses a `.npy` file holding little endian f32 values. Arrays of any shape are flattened, but
+/// must be in C order unless they only have one dimension.
+pub fn parse_npy(data: &[u8]) -> io::Result<Vec<f32>> {
+    if data.len() < NPY_PREAMBLE_LEN || &data[0..6] != NPY_MAGIC {
+        return Err(invalid_policy("not a .npy file".to_string()));
+    }
+    // Version 1.0 has a two byte header length, later versions four.
+    let (header_start, header_len) = match data[6] {
+        1 => (10, u16::from_le_bytes([data[8], data[9]]) as usize),
+        2 | 3 if data.len() >= 12 => (
+            12,
+            u32::from_le_bytes(data[8..12].try_into().unwrap()) as usize,
+        ),
+        major => {
+            return Err(invalid_policy(format!(
+                "unsupported .npy version {}",
+                major
+            )))
+        }
+    };
+    let header = data
+        .get(header_start..header_start + header_len)
+        .and_then(|header| std::str::from_utf8(header).ok())
+        .ok_or_else(|| invalid_policy("truncated .npy header".to_string()))?;
+
+    let descr = npy_header_value(header, "descr")
+        .and_then(|value| value.strip_prefix('\''))
+        .and_then(|value| value.split('\'').next())
+        .ok_or_else(|| invalid_policy("missing dtype".to_string()))?;
+    if descr != "<f4" {
+        return Err(invalid_policy(format!(
+            "dtype {} is not little endian f32",
+            descr
+        )));
+    }
+    let shape = npy_header_value(header, "shape")
+        .and_then(|value| value.strip_prefix('('))
+        .and_then(|value| value.split(')').next())
+        .ok_or_else(|| invalid_policy("missing shape".to_string()))?
+        .split(',')
+        .map(str::trim)
+        .filter(|dimension| !dimension.is_empty())
+        .map(|dimension| dimension.parse::<usize>())
+        .collect::<std::result::Result<Vec<usize>, _>>()
+        .map_err(|_| invalid_policy("invalid shape".to_string()))?;
+    let fortran_order = npy_header_value(header, "fortran_order")
+        .ok_or_else(|| invalid_policy("missing fortran_order".to_string()))?;
+    if fortran_order.starts_with("True") && shape.len() > 1 {
+        return Err(invalid_policy(
+            "Fortran ordered arrays are not supported".to_string(),
+        ));
+    }
+
+    let values = shape
+        .iter()
+        .try_fold(1usize, |count, dimension| count.checked_mul(*dimension))
+        .ok_or_else(|| invalid_policy("shape is too large".to_string()))?;
+    let body = &data[header_start + header_len..];
+    if values.checked_mul(4) != Some(body.len()) {
+        return Err(invalid_policy(format!(
+            "shape {:?} needs {} values, found {} bytes",
+            shape,
+            values,
+            body.len()
+        )));
+    }
+    parse_raw_f32(body)
+}
+
+/// The text following `'key':` in a `.npy` header dictionary.
+fn npy_header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let key_start = header.find(&format!("'{}'", key))?;
+    let rest = header[key_start + key.len() + 2..].trim_start();
+    Some(rest.strip_prefix(':')?.trim_start())
+}
+
+/// Encodes a policy as a one dimensional version 1.0 `.npy` file.
+pub fn write_npy(policy: &[f32]) -> Vec<u8> {
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({},), }}",
+        policy.len()
+    );
+    // The header is padded with spaces and ends in a newline so the data is aligned.
+    let unpadded_len = NPY_PREAMBLE_LEN + header.len() + 1;
+    let padding = (NPY_ALIGNMENT - unpadded_len % NPY_ALIGNMENT) % NPY_ALIGNMENT;
+    header.extend(std::iter::repeat_n(' ', padding));
+    header.push('\n');
+
+    let mut data = Vec::with_capacity(NPY_PREAMBLE_LEN + header.len() + policy.len() * 4);
+    data.extend_from_slice(NPY_MAGIC);
+    data.extend_from_slice(&[1, 0]);
+    data.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    data.extend_from_slice(header.as_bytes());
+    data.extend(write_raw_f32(policy));
+    data
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::common::NoiseSign;
     use rand::SeedableRng;
     use rand_xoshiro::Xoroshiro128Plus;
     use rayon::prelude::{
@@ -124,4 +384,81 @@ mod tests {
                 );
             });
     }
+
+    #[test]
+    fn test_mirrored_sampling() {
+        let mut sampler = super::PerturbationSampler::new(super::SamplingMode::Mirrored);
+        let (seed, sign) = sampler.next_perturbation().unwrap();
+        assert_eq!(sign, NoiseSign::Positive);
+        assert_eq!(
+            sampler.next_perturbation(),
+            Some((seed, NoiseSign::Negative)),
+            "Mirrored sampling should reuse the seed with a negative sign."
+        );
+        let (seed_2, sign_2) = sampler.next_perturbation().unwrap();
+        assert_ne!(seed, seed_2, "A new pair should use a new seed.");
+        assert_eq!(sign_2, NoiseSign::Positive);
+
+        let policy = create_sized_buffer(TEST_BUFFER_SIZE);
+        let mut positive = create_sized_buffer(TEST_BUFFER_SIZE);
+        let mut negative = create_sized_buffer(TEST_BUFFER_SIZE);
+        super::permute_parameters_with_seed(&policy, &mut positive, 0.1, seed, NoiseSign::Positive);
+        super::permute_parameters_with_seed(&policy, &mut negative, 0.1, seed, NoiseSign::Negative);
+        positive
+            .par_iter()
+            .zip(negative.par_iter())
+            .for_each(|(p, n)| assert_eq!(*p, -*n, "Mirrored perturbations should be opposite."));
+    }
+
+    #[test]
+    fn assigned_seeds_replace_random_ones() {
+        let mut sampler = super::PerturbationSampler::new(super::SamplingMode::Mirrored);
+        assert!(!sampler.is_waiting_for_seeds());
+        sampler.assign(vec![3, 5]);
+        let perturbations: Vec<_> = std::iter::from_fn(|| sampler.next_perturbation()).collect();
+        assert_eq!(
+            perturbations,
+            [
+                (3, NoiseSign::Positive),
+                (3, NoiseSign::Negative),
+                (5, NoiseSign::Positive),
+                (5, NoiseSign::Negative)
+            ]
+        );
+        assert!(sampler.is_waiting_for_seeds());
+
+        sampler.reset();
+        assert!(
+            sampler.next_perturbation().is_some(),
+            "A reset sampler draws its own seeds again."
+        );
+    }
+
+    #[test]
+    fn loads_repository_policies() {
+        let root = std::path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        let npy = super::load_policy(&root.join("policy_55601.npy")).unwrap();
+        let raw = super::load_policy(&root.join("policy_301.raw")).unwrap();
+        assert_eq!(npy.len(), 82394);
+        assert_eq!(raw.len(), 82394);
+        assert!(npy.iter().chain(raw.iter()).all(|value| value.is_finite()));
+    }
+
+    #[test]
+    fn npy_round_trips() {
+        let policy: Vec<f32> = (0..1001).map(|i| i as f32 * -0.25).collect();
+        let data = super::write_npy(&policy);
+        let data_offset = data.len() - policy.len() * 4;
+        assert_eq!(data_offset % 64, 0, "The data should be aligned.");
+        assert_eq!(data[data_offset - 1], b'\n');
+        assert_eq!(super::parse_npy(&data).unwrap(), policy);
+
+        // Other dtypes and truncated data are rejected.
+        let mut float64 = data.clone();
+        let descr = data.windows(3).position(|bytes| bytes == b"<f4").unwrap();
+        float64[descr + 2] = b'8';
+        assert!(super::parse_npy(&float64).is_err());
+        assert!(super::parse_npy(&data[..data.len() - 4]).is_err());
+        assert!(super::parse_raw_f32(&data[..6]).is_err());
+    }
 }
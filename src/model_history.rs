@@ -0,0 +1,174 @@
+use crate::common::{model_digest, ModelDelta, ModelVersion};
+use fnv::FnvHashMap;
+use std::sync::Arc;
+
+/// The latest model and the versions before it that are still recent enough to be in use.
+///
+/// Snapshots are shared, so a transfer or gradient update holding one keeps it alive after it
+/// has been evicted here. At most `maximum_age + 1` versions are retained, along with the deltas
+/// that produced them when the optimizer allows them.
+pub struct ModelHistory {
+    models: FnvHashMap<ModelVersion, Arc<Vec<f32>>>,
+    digests: FnvHashMap<ModelVersion, u64>,
+    /// Keyed by the version each delta produces.
+    deltas: FnvHashMap<ModelVersion, ModelDelta>,
+    latest_version: ModelVersion,
+    maximum_age: ModelVersion,
+}
+
+impl ModelHistory {
+    pub fn new(model_version: ModelVersion, model: Vec<f32>, maximum_age: ModelVersion) -> Self {
+        let mut digests = FnvHashMap::default();
+        digests.insert(model_version, model_digest(&model));
+        let mut models = FnvHashMap::default();
+        models.insert(model_version, Arc::new(model));
+        ModelHistory {
+            models,
+            digests,
+            deltas: FnvHashMap::default(),
+            latest_version: model_version,
+            maximum_age,
+        }
+    }
+
+    /// Adds the next version of the model and the delta to it from the previous version, if
+    /// there is one, evicting versions that are now too old.
+    pub fn push(&mut self, model: Vec<f32>, delta: Option<ModelDelta>) -> ModelVersion {
+        self.latest_version += 1;
+        self.digests
+            .insert(self.latest_version, model_digest(&model));
+        self.models.insert(self.latest_version, Arc::new(model));
+        if let Some(delta) = delta {
+            self.deltas.insert(self.latest_version, delta);
+        }
+        let latest_version = self.latest_version;
+        let maximum_age = self.maximum_age;
+        self.models
+            .retain(|version, _| latest_version - *version <= maximum_age);
+        self.digests
+            .retain(|version, _| latest_version - *version <= maximum_age);
+        self.deltas
+            .retain(|version, _| latest_version - *version <= maximum_age);
+        self.latest_version
+    }
+
+    pub fn latest_version(&self) -> ModelVersion {
+        self.latest_version
+    }
+
+    pub fn latest(&self) -> &Arc<Vec<f32>> {
+        &self.models[&self.latest_version]
+    }
+
+    /// The snapshot of `model_version`, if it has not been evicted.
+    pub fn get(&self, model_version: ModelVersion) -> Option<&Arc<Vec<f32>>> {
+        self.models.get(&model_version)
+    }
+
+    /// The `model_digest` of `model_version`, if it has not been evicted.
+    pub fn digest(&self, model_version: ModelVersion) -> Option<u64> {
+        self.digests.get(&model_version).copied()
+    }
+
+    pub fn latest_digest(&self) -> u64 {
+        self.digests[&self.latest_version]
+    }
+
+    /// The deltas that bring `model_version` up to the latest version, in order, or None if the
+    /// version is too old or one of the updates since has no delta.
+    pub fn deltas_since(
+        &self,
+        model_version: ModelVersion,
+    ) -> Option<Vec<(ModelVersion, &ModelDelta)>> {
+        if !self.models.contains_key(&model_version) {
+            return None;
+        }
+        (model_version + 1..=self.latest_version)
+            .map(|version| Some((version, self.deltas.get(&version)?)))
+            .collect()
+    }
+
+    pub fn parameter_count(&self) -> usize {
+        self.latest().len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.models.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.models.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ModelHistory;
+    use crate::common::{model_digest, ModelDelta};
+    use crate::gradient::NoiseGradient;
+    use crate::optimizer::{StatelessStep, WeightDecay};
+    use std::sync::Arc;
+
+    fn delta(model_digest: u64) -> Option<ModelDelta> {
+        Some(ModelDelta {
+            gradient: NoiseGradient {
+                terms: Vec::new(),
+                scale: 1.0,
+            },
+            step: StatelessStep {
+                learning_rate: 0.1,
+                weight_decay: WeightDecay::None,
+            },
+            model_digest,
+        })
+    }
+
+    #[test]
+    fn evicts_old_versions() {
+        let mut history = ModelHistory::new(5, vec![5.0], 2);
+        let snapshot = Arc::clone(history.latest());
+        for version in 6..=9 {
+            assert_eq!(history.push(vec![version as f32], None), version);
+        }
+        assert_eq!(history.latest_version(), 9);
+        assert_eq!(history.len(), 3);
+        assert!(history.get(6).is_none());
+        assert!(history.digest(6).is_none());
+        assert_eq!(history.latest_digest(), model_digest(&[9.0]));
+        assert_eq!(history.get(7).map(|model| model[0]), Some(7.0));
+        assert_eq!(history.latest()[0], 9.0);
+        assert_eq!(
+            *snapshot,
+            vec![5.0],
+            "Evicted snapshots live on while they are shared."
+        );
+    }
+
+    #[test]
+    fn deltas_cover_recent_versions() {
+        let mut history = ModelHistory::new(0, vec![0.0], 2);
+        history.push(vec![1.0], delta(1));
+        history.push(vec![2.0], delta(2));
+        let hashes = |deltas: Vec<(u32, &ModelDelta)>| -> Vec<(u32, u64)> {
+            deltas
+                .into_iter()
+                .map(|(version, delta)| (version, delta.model_digest))
+                .collect()
+        };
+        assert_eq!(hashes(history.deltas_since(0).unwrap()), [(1, 1), (2, 2)]);
+        assert_eq!(hashes(history.deltas_since(2).unwrap()), []);
+        assert!(history.deltas_since(3).is_none());
+
+        history.push(vec![3.0], None);
+        history.push(vec![4.0], delta(4));
+        assert!(
+            history.deltas_since(1).is_none(),
+            "Version 1 is too old for deltas."
+        );
+        assert!(
+            history.deltas_since(2).is_none(),
+            "Version 3 has no delta, so it must be sent in full."
+        );
+        assert_eq!(hashes(history.deltas_since(3).unwrap()), [(4, 4)]);
+    }
+}
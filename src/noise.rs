@@ -2,11 +2,15 @@ use rand::distributions::Open01;
 use rand::distributions::Uniform;
 use rand::prelude::Distribution;
 use rand::Rng;
+use rand::SeedableRng;
 use rand_xoshiro::Xoroshiro128Plus;
 use rayon::prelude::ParallelBridge;
 use rayon::prelude::ParallelIterator;
+use serde::{Deserialize, Serialize};
+use std::iter;
 
 use crate::collect_slice::collect_slice;
+use crate::kernels::{self, Kernel};
 
 /// Uniform distribution between -1.0 and 1.0, both exclusive.
 struct UniformNoise;
@@ -33,15 +37,85 @@ pub fn par_fill_noise_uniform(mut rng: Xoroshiro128Plus, buffer: &mut [f32]) {
         });
 }
 
-pub fn par_fill_noise_standard(mut rng: Xoroshiro128Plus, buffer: &mut [f32]) {
-    // Check that buffer length divides by 2.
-    assert!(buffer.len() % 2 == 0);
+/// Values in each independently generated block of the noise stream.
+pub const NOISE_BLOCK_LEN: usize = 100_000;
 
-    const MU: f32 = 0.0;
-    const SIGMA: f32 = 1.0;
+/// Identifies how the standard normal noise stream for a seed is generated, so that a learner
+/// and its workers can check they produce the same noise bit for bit.
+///
+/// Version 1:
+/// - The base generator is `Xoroshiro128Plus::seed_from_u64(seed)`.
+/// - The stream is split into blocks of `NOISE_BLOCK_LEN` values, block `k` is drawn from a copy
+///   of the base generator after `k + 1` jumps.
+/// - Each block samples f32 uniforms from `Uniform::new(f32::EPSILON, 1.0)` and turns each pair
+///   `(u1, u2)` into `(r * cos(2 * PI * u2), r * sin(2 * PI * u2))` where `r = sqrt(-2 * ln(u1))`,
+///   all in f32 with the `ln`, `sin` and `cos` of the standard library.
+///
+/// Version 2, generated by `par_fill_noise_standard`, is version 1 with each pair turned into
+/// normal values by `kernels::box_muller_pair` instead, whose polynomial `ln`, `sin` and `cos`
+/// the vector kernels compute bit for bit. Its values are within a few millionths of version 1.
+///
+/// A buffer of any length holds the start of the stream, whatever the number of threads.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct NoiseSpec {
+    pub version: u32,
+}
+
+impl NoiseSpec {
+    pub const V1: NoiseSpec = NoiseSpec { version: 1 };
+    pub const V2: NoiseSpec = NoiseSpec { version: 2 };
+    /// The specification this build generates noise with.
+    pub const CURRENT: NoiseSpec = NoiseSpec::V2;
+
+    pub fn is_supported(self) -> bool {
+        self == NoiseSpec::CURRENT
+    }
 
+    fn transform_pair(self, u1: f32, u2: f32) -> (f32, f32) {
+        if self == NoiseSpec::V1 {
+            box_muller(u1, u2)
+        } else {
+            kernels::box_muller_pair(u1, u2)
+        }
+    }
+
+    /// Fills `out` with normal values from the next uniforms, drawing one extra uniform to finish
+    /// the last pair of an odd length and keeping only its first value.
+    fn fill_from(self, uniforms: &mut impl Iterator<Item = f32>, out: &mut [f32]) {
+        collect_slice(&mut *uniforms, out);
+        if self == NoiseSpec::V1 {
+            for pair in out.chunks_exact_mut(2) {
+                (pair[0], pair[1]) = box_muller(pair[0], pair[1]);
+            }
+        } else {
+            Kernel::detect().box_muller(out);
+        }
+        if out.len() % 2 == 1 {
+            let last = out.last_mut().unwrap();
+            *last = self.transform_pair(*last, uniforms.next().unwrap()).0;
+        }
+    }
+}
+
+/// Turns two uniform values into two standard normal values with the Box-Muller transform.
+fn box_muller(u1: f32, u2: f32) -> (f32, f32) {
+    // As u1 approaches 0.0, log(u1) approaches infinity, the uniform distribution is lower clamped to 0 + EPSILON.
+    // For f32 the min/max expected values could be as large as sqrt(-2 * log(1.19209290e-07)) = 5.64666
+    let mag = (-2.0 * u1.ln()).sqrt();
+    let z0 = mag * (2.0 * std::f32::consts::PI * u2).cos();
+    let z1 = mag * (2.0 * std::f32::consts::PI * u2).sin();
+    (z0, z1)
+}
+
+/// Fills `buffer` with the start of the standard normal noise stream for `rng`, as described by
+/// `NoiseSpec::CURRENT`.
+pub fn par_fill_noise_standard(rng: Xoroshiro128Plus, buffer: &mut [f32]) {
+    par_fill_noise_with_spec(NoiseSpec::CURRENT, rng, buffer);
+}
+
+fn par_fill_noise_with_spec(spec: NoiseSpec, mut rng: Xoroshiro128Plus, buffer: &mut [f32]) {
     buffer
-        .chunks_mut(100_000)
+        .chunks_mut(NOISE_BLOCK_LEN)
         .map(|chunk| {
             (
                 jump_and_clone(&mut rng).sample_iter(Uniform::new(f32::EPSILON, 1.0)),
@@ -49,26 +123,65 @@ pub fn par_fill_noise_standard(mut rng: Xoroshiro128Plus, buffer: &mut [f32]) {
             )
         })
         .par_bridge()
-        .for_each(|(mut rng, chunk)| {
-            collect_slice(&mut rng, chunk);
-            chunk.chunks_mut(2).for_each(|pair| {
-                // Capture 2 uniform values, and make them into a standard normal.
-                let (u1, u2) = (pair[0], pair[1]);
-                // As u1 approaches 0.0, log(u1) approaches infinity, the uniform distribution is lower clamped to 0 + EPSILON.
-                // For f32 the min/max expected values could be as large as sqrt(-2 * log(1.19209290e-07)) = 5.64666
-                let mag = SIGMA * (-2.0 * u1.ln()).sqrt();
-                let z0 = mag * (2.0 * std::f32::consts::PI * u2).cos() + MU;
-                let z1 = mag * (2.0 * std::f32::consts::PI * u2).sin() + MU;
-
-                pair[0] = z0;
-                pair[1] = z1;
+        .for_each(|(mut rng, chunk)| spec.fill_from(&mut rng, chunk));
+}
+
+/// Noise that can be generated from anywhere in the stream for a seed.
+pub trait NoiseSource {
+    /// Fills `out` with values `start..start + out.len()` of the noise stream for `seed`.
+    fn fill_range(&self, seed: u64, start: usize, out: &mut [f32]);
+}
+
+/// Random access into the `NoiseSpec::CURRENT` stream, giving the same values as
+/// `par_fill_noise_standard` bit for bit.
+///
+/// Every block of the stream has its own generator, reached by jumping the seed's generator, so
+/// starting at any index discards at most one block of draws rather than everything before it.
+pub struct BlockJumpNoise;
+
+impl NoiseSource for BlockJumpNoise {
+    fn fill_range(&self, seed: u64, start: usize, out: &mut [f32]) {
+        let mut rng = Xoroshiro128Plus::seed_from_u64(seed);
+        for _ in 0..start / NOISE_BLOCK_LEN {
+            rng.jump();
+        }
+        // Split `out` into pieces that each lie in one block, the first starting partway in.
+        let block_offset = start % NOISE_BLOCK_LEN;
+        let (head, tail) = out.split_at_mut((NOISE_BLOCK_LEN - block_offset).min(out.len()));
+        iter::once((block_offset, head))
+            .chain(tail.chunks_mut(NOISE_BLOCK_LEN).map(|piece| (0, piece)))
+            .map(|(block_offset, piece)| (jump_and_clone(&mut rng), block_offset, piece))
+            .par_bridge()
+            .for_each(|(rng, block_offset, piece)| {
+                fill_block_from(NoiseSpec::CURRENT, rng, block_offset, piece);
             });
-        });
+    }
+}
+
+/// Fills `out` with the values of a block from `block_offset` on, given the block's generator.
+fn fill_block_from(spec: NoiseSpec, rng: Xoroshiro128Plus, block_offset: usize, out: &mut [f32]) {
+    let mut uniforms = rng.sample_iter(Uniform::new(f32::EPSILON, 1.0));
+    // Values come in pairs, so start from the pair holding the first value wanted.
+    let pair_start = block_offset - block_offset % 2;
+    if pair_start > 0 {
+        uniforms.nth(pair_start - 1);
+    }
+    match out {
+        [first, rest @ ..] if block_offset % 2 == 1 => {
+            let (u1, u2) = (uniforms.next().unwrap(), uniforms.next().unwrap());
+            *first = spec.transform_pair(u1, u2).1;
+            spec.fill_from(&mut uniforms, rest);
+        }
+        _ => spec.fill_from(&mut uniforms, out),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::par_fill_noise_uniform;
+    use super::{
+        par_fill_noise_standard, par_fill_noise_uniform, par_fill_noise_with_spec, BlockJumpNoise,
+        NoiseSource, NoiseSpec, NOISE_BLOCK_LEN,
+    };
     use rand::SeedableRng;
     use rand_xoshiro::Xoroshiro128Plus;
 
@@ -190,4 +303,176 @@ mod tests {
                 }
             });
     }
+
+    fn standard_noise_with_threads(threads: usize, seed: u64, len: usize) -> Vec<f32> {
+        with_threads(threads, || {
+            let mut buf = create_sized_buffer(len);
+            par_fill_noise_standard(Xoroshiro128Plus::seed_from_u64(seed), &mut buf);
+            buf
+        })
+    }
+
+    fn noise_with_spec(spec: NoiseSpec, threads: usize, seed: u64, len: usize) -> Vec<f32> {
+        with_threads(threads, || {
+            let mut buf = create_sized_buffer(len);
+            par_fill_noise_with_spec(spec, Xoroshiro128Plus::seed_from_u64(seed), &mut buf);
+            buf
+        })
+    }
+
+    fn with_threads(threads: usize, fill: impl FnOnce() -> Vec<f32> + Send) -> Vec<f32> {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .expect("Creating thread pool")
+            .install(fill)
+    }
+
+    #[test]
+    fn standard_noise_is_independent_of_thread_count() {
+        for len in [1, 100, NOISE_BLOCK_LEN + 1, 1_999_007] {
+            let one_thread = standard_noise_with_threads(1, 0xFEDCBA98, len);
+            for threads in [3, 8] {
+                let buf = standard_noise_with_threads(threads, 0xFEDCBA98, len);
+                // Compared as bits so that any NaN would fail too.
+                assert!(
+                    buf.iter()
+                        .zip(&one_thread)
+                        .all(|(a, b)| a.to_bits() == b.to_bits()),
+                    "{} values differ between 1 and {} threads.",
+                    len,
+                    threads
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn standard_noise_of_any_length_is_a_prefix() {
+        let stream = standard_noise_with_threads(0, 0x12345678, 2 * NOISE_BLOCK_LEN + 2);
+        for len in [
+            1,
+            2,
+            3,
+            NOISE_BLOCK_LEN - 1,
+            NOISE_BLOCK_LEN + 1,
+            2 * NOISE_BLOCK_LEN + 1,
+        ] {
+            let buf = standard_noise_with_threads(0, 0x12345678, len);
+            assert_eq!(buf, stream[..len], "Buffer of {} values.", len);
+        }
+    }
+
+    #[test]
+    fn standard_noise_matches_golden_values() {
+        // Any change here breaks learners and workers that share noise across builds and must
+        // come with a new NoiseSpec version.
+        let golden_v1 = [
+            (0, 0xbf50f70b),
+            (1, 0x3e9d850f),
+            (2, 0x3fe736f4),
+            (99_999, 0x3ee8735b),
+            (100_000, 0xbf2b8738),
+            (100_001, 0x3e231907),
+            (200_000, 0x3e27e718),
+            (200_001, 0x3f94c360),
+        ];
+        let golden_v2 = [
+            (0, 0xbf50f70b),
+            (1, 0x3e9d850f),
+            (2, 0x3fe736f4),
+            (99_999, 0x3ee8735b),
+            (100_000, 0xbf2b8738),
+            (100_001, 0x3e231907),
+            (200_000, 0x3e27e719),
+            (200_001, 0x3f94c360),
+        ];
+        for (spec, golden) in [(NoiseSpec::V1, golden_v1), (NoiseSpec::V2, golden_v2)] {
+            for threads in [1, 3] {
+                let buf = noise_with_spec(spec, threads, 0x5EED, 200_002);
+                for (index, bits) in golden {
+                    assert_eq!(
+                        buf[index].to_bits(),
+                        bits,
+                        "{:?} value {} changed.",
+                        spec,
+                        index
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn standard_noise_versions_are_close() {
+        let v1 = noise_with_spec(NoiseSpec::V1, 0, 0x5EED, NOISE_BLOCK_LEN + 1);
+        let v2 = noise_with_spec(NoiseSpec::V2, 0, 0x5EED, NOISE_BLOCK_LEN + 1);
+        for (index, (a, b)) in v1.iter().zip(&v2).enumerate() {
+            assert!(
+                (a - b).abs() <= 4e-6,
+                "Value {}: {} against {}.",
+                index,
+                a,
+                b
+            );
+        }
+    }
+
+    #[test]
+    fn block_jump_noise_matches_the_stream() {
+        let stream = standard_noise_with_threads(0, 0x5EED, 3 * NOISE_BLOCK_LEN + 1);
+        let ranges = [
+            (0, 0),
+            (0, 7),
+            (1, 2),
+            (3, 4),
+            (NOISE_BLOCK_LEN - 1, 2),
+            (NOISE_BLOCK_LEN, 1),
+            (NOISE_BLOCK_LEN + 12_345, NOISE_BLOCK_LEN + 1),
+            (2 * NOISE_BLOCK_LEN - 3, NOISE_BLOCK_LEN + 4),
+            (3 * NOISE_BLOCK_LEN, 1),
+        ];
+        for threads in [1, 3] {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .expect("Creating thread pool")
+                .install(|| {
+                    for (start, len) in ranges {
+                        let mut out = create_sized_buffer(len);
+                        BlockJumpNoise.fill_range(0x5EED, start, &mut out);
+                        assert_eq!(
+                            out,
+                            stream[start..start + len],
+                            "{} values from {} with {} threads.",
+                            len,
+                            start,
+                            threads
+                        );
+                    }
+                });
+        }
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with cargo test --release -- --ignored --nocapture"]
+    fn benchmark_standard_noise() {
+        const LEN: usize = 10_000_000;
+        let mut buf = create_sized_buffer(LEN);
+        for spec in [NoiseSpec::V1, NoiseSpec::V2] {
+            let start = std::time::Instant::now();
+            par_fill_noise_with_spec(spec, Xoroshiro128Plus::seed_from_u64(0), &mut buf);
+            println!(
+                "par_fill_noise_standard {:?}: {:.3} ns per value",
+                spec,
+                start.elapsed().as_nanos() as f64 / LEN as f64
+            );
+        }
+        let start = std::time::Instant::now();
+        BlockJumpNoise.fill_range(0, LEN / 2, &mut buf[..LEN / 2]);
+        println!(
+            "BlockJumpNoise::fill_range: {:.3} ns per value",
+            start.elapsed().as_nanos() as f64 / (LEN / 2) as f64
+        );
+    }
 }
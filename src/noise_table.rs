@@ -0,0 +1,91 @@
+use crate::noise::par_fill_noise_standard;
+use rand::SeedableRng;
+use rand_xoshiro::Xoroshiro128Plus;
+use serde::{Deserialize, Serialize};
+
+/// Identifies a noise table so that workers can regenerate the learner's table locally.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct NoiseTableConfig {
+    pub seed: u64,
+    pub size: usize,
+}
+
+/// A block of standard normal noise shared by the learner and its workers.
+///
+/// The table holds the first `size` values of the noise stream for `seed`, so a slice at
+/// `offset` is the same noise `model::reconstruct_noise(seed, offset, ..)` would produce.
+pub struct NoiseTable {
+    config: NoiseTableConfig,
+    noise: Vec<f32>,
+}
+
+impl NoiseTable {
+    pub fn new(config: NoiseTableConfig) -> NoiseTable {
+        let mut noise = vec![0.0; config.size];
+        par_fill_noise_standard(Xoroshiro128Plus::seed_from_u64(config.seed), &mut noise);
+        NoiseTable { config, noise }
+    }
+
+    pub fn config(&self) -> NoiseTableConfig {
+        self.config
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.config.seed
+    }
+
+    pub fn len(&self) -> usize {
+        self.noise.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.noise.is_empty()
+    }
+
+    /// Returns `size` noise values starting at `offset`, or None if they run past the table.
+    pub fn get(&self, offset: usize, size: usize) -> Option<&[f32]> {
+        self.noise.get(offset..offset.checked_add(size)?)
+    }
+
+    /// Maps a random draw onto an offset where a slice of `size` values fits in the table.
+    pub fn offset_for(&self, draw: u64, size: usize) -> Option<usize> {
+        let offset_count = self.noise.len().checked_sub(size)? + 1;
+        Some((draw % offset_count as u64) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NoiseTable, NoiseTableConfig};
+    use crate::model::reconstruct_noise;
+
+    #[test]
+    fn table_slices_match_reconstructed_noise() {
+        let table = NoiseTable::new(NoiseTableConfig {
+            seed: 0xC0FFEE,
+            size: 300_001,
+        });
+        assert_eq!(table.len(), 300_001);
+
+        for offset in [0, 1, 99_999, 123_457, 200_001] {
+            let mut noise = vec![0.0; 100_000];
+            reconstruct_noise(0xC0FFEE, offset, &mut noise);
+            assert_eq!(
+                table.get(offset, 100_000).unwrap(),
+                noise.as_slice(),
+                "Noise table should hold a prefix of the noise stream."
+            );
+        }
+    }
+
+    #[test]
+    fn offsets_stay_inside_table() {
+        let table = NoiseTable::new(NoiseTableConfig { seed: 1, size: 10 });
+        for draw in 0..100 {
+            let offset = table.offset_for(draw, 4).unwrap();
+            assert!(table.get(offset, 4).is_some());
+        }
+        assert_eq!(table.offset_for(0, 11), None);
+        assert_eq!(table.get(8, 4), None);
+    }
+}
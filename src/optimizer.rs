@@ -0,0 +1,474 @@
+use crate::model::PAR_CHUNK_SIZE;
+use rayon::{
+    prelude::{IndexedParallelIterator, ParallelIterator},
+    slice::{ParallelSlice, ParallelSliceMut},
+};
+use serde::{Deserialize, Serialize};
+
+/// Turns gradient estimates into model updates. Evolution strategies maximise the reward, so
+/// every optimizer steps along the gradient rather than against it.
+pub trait Optimizer {
+    /// Updates `model` in place from an estimate of the reward gradient.
+    fn step(&mut self, model: &mut [f32], gradient: &[f32]);
+
+    fn learning_rate(&self) -> f32;
+}
+
+/// Pulls the parameters towards zero on every step.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum WeightDecay {
+    None,
+    /// Adds `-decay * parameter` to the gradient, so adaptive optimizers rescale it as well.
+    L2(f32),
+    /// Shrinks each parameter by `learning_rate * decay * parameter`, separately from the
+    /// gradient step.
+    Decoupled(f32),
+}
+
+impl WeightDecay {
+    fn l2(self) -> f32 {
+        match self {
+            WeightDecay::L2(decay) => decay,
+            _ => 0.0,
+        }
+    }
+
+    fn decoupled(self) -> f32 {
+        match self {
+            WeightDecay::Decoupled(decay) => decay,
+            _ => 0.0,
+        }
+    }
+}
+
+/// Stochastic gradient ascent with optional momentum.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Sgd {
+    pub learning_rate: f32,
+    pub momentum: f32,
+    pub weight_decay: WeightDecay,
+    velocity: Vec<f32>,
+}
+
+impl Sgd {
+    pub fn new(
+        parameter_count: usize,
+        learning_rate: f32,
+        momentum: f32,
+        weight_decay: WeightDecay,
+    ) -> Sgd {
+        Sgd {
+            learning_rate,
+            momentum,
+            weight_decay,
+            velocity: vec![0.0; parameter_count],
+        }
+    }
+}
+
+impl Optimizer for Sgd {
+    fn step(&mut self, model: &mut [f32], gradient: &[f32]) {
+        assert_eq!(model.len(), gradient.len());
+        assert_eq!(model.len(), self.velocity.len());
+        let Sgd {
+            learning_rate,
+            momentum,
+            weight_decay,
+            ..
+        } = *self;
+        let (l2, decoupled) = (weight_decay.l2(), weight_decay.decoupled());
+        model
+            .par_chunks_mut(PAR_CHUNK_SIZE)
+            .zip(self.velocity.par_chunks_mut(PAR_CHUNK_SIZE))
+            .zip(gradient.par_chunks(PAR_CHUNK_SIZE))
+            .for_each(|((model_chunk, velocity_chunk), gradient_chunk)| {
+                for ((parameter, velocity), gradient) in model_chunk
+                    .iter_mut()
+                    .zip(velocity_chunk.iter_mut())
+                    .zip(gradient_chunk)
+                {
+                    *velocity = momentum * *velocity + gradient - l2 * *parameter;
+                    *parameter += learning_rate * (*velocity - decoupled * *parameter);
+                }
+            });
+    }
+
+    fn learning_rate(&self) -> f32 {
+        self.learning_rate
+    }
+}
+
+/// Adam, with bias corrected first and second moment estimates of the gradient.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Adam {
+    pub learning_rate: f32,
+    pub beta1: f32,
+    pub beta2: f32,
+    pub epsilon: f32,
+    pub weight_decay: WeightDecay,
+    step_count: u64,
+    first_moment: Vec<f32>,
+    second_moment: Vec<f32>,
+}
+
+impl Adam {
+    pub fn new(
+        parameter_count: usize,
+        learning_rate: f32,
+        beta1: f32,
+        beta2: f32,
+        epsilon: f32,
+        weight_decay: WeightDecay,
+    ) -> Adam {
+        Adam {
+            learning_rate,
+            beta1,
+            beta2,
+            epsilon,
+            weight_decay,
+            step_count: 0,
+            first_moment: vec![0.0; parameter_count],
+            second_moment: vec![0.0; parameter_count],
+        }
+    }
+
+    pub fn step_count(&self) -> u64 {
+        self.step_count
+    }
+}
+
+impl Optimizer for Adam {
+    fn step(&mut self, model: &mut [f32], gradient: &[f32]) {
+        assert_eq!(model.len(), gradient.len());
+        assert_eq!(model.len(), self.first_moment.len());
+        self.step_count += 1;
+        let Adam {
+            learning_rate,
+            beta1,
+            beta2,
+            epsilon,
+            weight_decay,
+            step_count,
+            ..
+        } = *self;
+        let (l2, decoupled) = (weight_decay.l2(), weight_decay.decoupled());
+        // Computed in f64, the f32 powers lose precision over long runs.
+        let step_count = step_count.min(i32::MAX as u64) as i32;
+        let first_correction = (1.0 - (beta1 as f64).powi(step_count)) as f32;
+        let second_correction = (1.0 - (beta2 as f64).powi(step_count)) as f32;
+        model
+            .par_chunks_mut(PAR_CHUNK_SIZE)
+            .zip(self.first_moment.par_chunks_mut(PAR_CHUNK_SIZE))
+            .zip(self.second_moment.par_chunks_mut(PAR_CHUNK_SIZE))
+            .zip(gradient.par_chunks(PAR_CHUNK_SIZE))
+            .for_each(
+                |(((model_chunk, first_chunk), second_chunk), gradient_chunk)| {
+                    for (((parameter, first), second), gradient) in model_chunk
+                        .iter_mut()
+                        .zip(first_chunk.iter_mut())
+                        .zip(second_chunk.iter_mut())
+                        .zip(gradient_chunk)
+                    {
+                        let gradient = gradient - l2 * *parameter;
+                        *first = beta1 * *first + (1.0 - beta1) * gradient;
+                        *second = beta2 * *second + (1.0 - beta2) * gradient * gradient;
+                        let update = (*first / first_correction)
+                            / ((*second / second_correction).sqrt() + epsilon);
+                        *parameter += learning_rate * (update - decoupled * *parameter);
+                    }
+                },
+            );
+    }
+
+    fn learning_rate(&self) -> f32 {
+        self.learning_rate
+    }
+}
+
+/// Any of the optimizers along with its moment buffers, as saved in checkpoints.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum OptimizerState {
+    Sgd(Sgd),
+    Adam(Adam),
+}
+
+impl Optimizer for OptimizerState {
+    fn step(&mut self, model: &mut [f32], gradient: &[f32]) {
+        match self {
+            OptimizerState::Sgd(sgd) => sgd.step(model, gradient),
+            OptimizerState::Adam(adam) => adam.step(model, gradient),
+        }
+    }
+
+    fn learning_rate(&self) -> f32 {
+        match self {
+            OptimizerState::Sgd(sgd) => sgd.learning_rate(),
+            OptimizerState::Adam(adam) => adam.learning_rate(),
+        }
+    }
+}
+
+impl OptimizerState {
+    /// The step this optimizer takes, if it depends on nothing but the model and the gradient.
+    /// Momentum and Adam's moments are state that only the learner holds.
+    pub fn stateless_step(&self) -> Option<StatelessStep> {
+        match self {
+            OptimizerState::Sgd(sgd) if sgd.momentum == 0.0 => Some(StatelessStep {
+                learning_rate: sgd.learning_rate,
+                weight_decay: sgd.weight_decay,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// An SGD step without momentum, which a worker holding the model can repeat exactly.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct StatelessStep {
+    pub learning_rate: f32,
+    pub weight_decay: WeightDecay,
+}
+
+impl StatelessStep {
+    pub fn apply(self, model: &mut [f32], gradient: &[f32]) {
+        assert_eq!(model.len(), gradient.len());
+        let learning_rate = self.learning_rate;
+        let (l2, decoupled) = (self.weight_decay.l2(), self.weight_decay.decoupled());
+        model
+            .par_chunks_mut(PAR_CHUNK_SIZE)
+            .zip(gradient.par_chunks(PAR_CHUNK_SIZE))
+            .for_each(|(model_chunk, gradient_chunk)| {
+                for (parameter, gradient) in model_chunk.iter_mut().zip(gradient_chunk) {
+                    // `Sgd::step` with a zero velocity, so the results match bit for bit.
+                    let velocity = gradient - l2 * *parameter;
+                    *parameter += learning_rate * (velocity - decoupled * *parameter);
+                }
+            });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OptimizerKind {
+    Sgd,
+    Adam,
+}
+
+/// Hyperparameters for building an optimizer. `momentum` only applies to SGD and the betas and
+/// epsilon only to Adam.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OptimizerConfig {
+    pub kind: OptimizerKind,
+    pub learning_rate: f32,
+    pub momentum: f32,
+    pub beta1: f32,
+    pub beta2: f32,
+    pub epsilon: f32,
+    pub weight_decay: WeightDecay,
+}
+
+impl Default for OptimizerConfig {
+    fn default() -> OptimizerConfig {
+        OptimizerConfig {
+            kind: OptimizerKind::Sgd,
+            learning_rate: 0.01,
+            momentum: 0.0,
+            beta1: 0.9,
+            beta2: 0.999,
+            epsilon: 1e-8,
+            weight_decay: WeightDecay::None,
+        }
+    }
+}
+
+impl OptimizerConfig {
+    pub fn build(&self, parameter_count: usize) -> OptimizerState {
+        match self.kind {
+            OptimizerKind::Sgd => OptimizerState::Sgd(Sgd::new(
+                parameter_count,
+                self.learning_rate,
+                self.momentum,
+                self.weight_decay,
+            )),
+            OptimizerKind::Adam => OptimizerState::Adam(Adam::new(
+                parameter_count,
+                self.learning_rate,
+                self.beta1,
+                self.beta2,
+                self.epsilon,
+                self.weight_decay,
+            )),
+        }
+    }
+
+    /// Carries on from saved state with these hyperparameters, so that settings such as the
+    /// learning rate can be changed when resuming. Returns None if the state is for a different
+    /// optimizer or model size.
+    pub fn resume(&self, state: OptimizerState, parameter_count: usize) -> Option<OptimizerState> {
+        match (self.kind, state) {
+            (OptimizerKind::Sgd, OptimizerState::Sgd(mut sgd))
+                if sgd.velocity.len() == parameter_count =>
+            {
+                sgd.learning_rate = self.learning_rate;
+                sgd.momentum = self.momentum;
+                sgd.weight_decay = self.weight_decay;
+                Some(OptimizerState::Sgd(sgd))
+            }
+            (OptimizerKind::Adam, OptimizerState::Adam(mut adam))
+                if adam.first_moment.len() == parameter_count =>
+            {
+                adam.learning_rate = self.learning_rate;
+                adam.beta1 = self.beta1;
+                adam.beta2 = self.beta2;
+                adam.epsilon = self.epsilon;
+                adam.weight_decay = self.weight_decay;
+                Some(OptimizerState::Adam(adam))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Adam, Optimizer, OptimizerConfig, OptimizerKind, Sgd, WeightDecay};
+    use crate::gradient::apply_gradient;
+
+    const TEST_BUFFER_SIZE: usize = 250_000;
+
+    fn test_gradient() -> Vec<f32> {
+        (0..TEST_BUFFER_SIZE)
+            .map(|i| (i % 7) as f32 - 3.0)
+            .collect()
+    }
+
+    #[test]
+    fn plain_sgd_is_a_gradient_step() {
+        let gradient = test_gradient();
+        let mut model = vec![1.0; TEST_BUFFER_SIZE];
+        let mut expected = model.clone();
+        Sgd::new(TEST_BUFFER_SIZE, 0.1, 0.0, WeightDecay::None).step(&mut model, &gradient);
+        apply_gradient(&mut expected, &gradient, 0.1);
+        assert_eq!(model, expected);
+    }
+
+    #[test]
+    fn momentum_accumulates() {
+        let gradient = vec![1.0; 4];
+        let mut model = vec![0.0; 4];
+        let mut sgd = Sgd::new(4, 0.5, 0.9, WeightDecay::None);
+        sgd.step(&mut model, &gradient);
+        sgd.step(&mut model, &gradient);
+        // 0.5 * 1.0 then 0.5 * (0.9 + 1.0)
+        assert!(model.iter().all(|p| (*p - 1.45).abs() < 1e-6));
+    }
+
+    #[test]
+    fn adam_first_step_is_the_learning_rate() {
+        let gradient = test_gradient();
+        let mut model = vec![0.0; TEST_BUFFER_SIZE];
+        let mut adam = Adam::new(TEST_BUFFER_SIZE, 0.01, 0.9, 0.999, 1e-8, WeightDecay::None);
+        adam.step(&mut model, &gradient);
+        assert_eq!(adam.step_count(), 1);
+        for (parameter, gradient) in model.iter().zip(&gradient) {
+            let expected = 0.01 * gradient.signum() * (*gradient != 0.0) as u8 as f32;
+            assert!(
+                (parameter - expected).abs() < 1e-6,
+                "Bias correction should make the first step the size of the learning rate."
+            );
+        }
+    }
+
+    #[test]
+    fn weight_decay_shrinks_parameters() {
+        let zero_gradient = vec![0.0; 4];
+        for weight_decay in [WeightDecay::L2(0.1), WeightDecay::Decoupled(0.1)] {
+            for kind in [OptimizerKind::Sgd, OptimizerKind::Adam] {
+                let mut optimizer = OptimizerConfig {
+                    kind,
+                    learning_rate: 0.01,
+                    weight_decay,
+                    ..OptimizerConfig::default()
+                }
+                .build(4);
+                let mut model = vec![2.0, -2.0, 2.0, -2.0];
+                optimizer.step(&mut model, &zero_gradient);
+                assert!(
+                    model.iter().all(|p| p.abs() < 2.0 && p.abs() > 1.9),
+                    "{:?} {:?} should move the parameters towards zero, got {:?}",
+                    kind,
+                    weight_decay,
+                    model
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn state_round_trips() {
+        let gradient = test_gradient();
+        let config = OptimizerConfig {
+            kind: OptimizerKind::Adam,
+            ..OptimizerConfig::default()
+        };
+        let mut optimizer = config.build(TEST_BUFFER_SIZE);
+        let mut model = vec![0.0; TEST_BUFFER_SIZE];
+        optimizer.step(&mut model, &gradient);
+
+        let saved = bincode::serialize(&optimizer).unwrap();
+        let mut restored = config
+            .resume(bincode::deserialize(&saved).unwrap(), TEST_BUFFER_SIZE)
+            .unwrap();
+        let mut restored_model = model.clone();
+        optimizer.step(&mut model, &gradient);
+        restored.step(&mut restored_model, &gradient);
+        assert_eq!(
+            model, restored_model,
+            "Restored state should continue identically."
+        );
+
+        let sgd = OptimizerConfig::default();
+        assert!(sgd.resume(restored, TEST_BUFFER_SIZE).is_none());
+    }
+
+    #[test]
+    fn stateless_step_repeats_the_learner() {
+        let mut gradient = test_gradient();
+        gradient[0] = -0.0;
+        for weight_decay in [
+            WeightDecay::None,
+            WeightDecay::L2(0.01),
+            WeightDecay::Decoupled(0.01),
+        ] {
+            let config = OptimizerConfig {
+                weight_decay,
+                ..OptimizerConfig::default()
+            };
+            let mut optimizer = config.build(TEST_BUFFER_SIZE);
+            let step = optimizer.stateless_step().unwrap();
+            let mut model = vec![1.0; TEST_BUFFER_SIZE];
+            model[0] = -0.0;
+            let mut worker_model = model.clone();
+            for _ in 0..3 {
+                optimizer.step(&mut model, &gradient);
+                step.apply(&mut worker_model, &gradient);
+            }
+            let bits = |model: &[f32]| {
+                model
+                    .iter()
+                    .map(|value| value.to_bits())
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(bits(&model), bits(&worker_model), "{:?}", weight_decay);
+        }
+
+        for kind in [OptimizerKind::Adam, OptimizerKind::Sgd] {
+            let stateful = OptimizerConfig {
+                kind,
+                momentum: 0.9,
+                ..OptimizerConfig::default()
+            };
+            assert!(stateful.build(4).stateless_step().is_none());
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use crate::common::{Episode, ModelVersion};
+
+/// How much an episode counts towards an update, by how many versions its model is behind.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StalenessWeighting {
+    /// Every usable episode counts fully.
+    Uniform,
+    /// Falls linearly from 1 for the latest model to `1 / (maximum_age + 1)` at the age limit.
+    Linear,
+    /// Multiplies the weight by the decay for every version behind.
+    Exponential(f32),
+}
+
+/// Decides which episodes are recent enough to use and how much each of them counts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StalenessPolicy {
+    pub weighting: StalenessWeighting,
+    /// Episodes from models more than this many versions behind are dropped.
+    pub maximum_age: ModelVersion,
+}
+
+impl StalenessPolicy {
+    /// The weight of an episode generated with `episode_version` when `latest_version` is the
+    /// newest model, or None if it is too old, or claims to be newer than the latest model.
+    pub fn weight(
+        &self,
+        episode_version: ModelVersion,
+        latest_version: ModelVersion,
+    ) -> Option<f32> {
+        let age = latest_version.checked_sub(episode_version)?;
+        if age > self.maximum_age {
+            return None;
+        }
+        Some(match self.weighting {
+            StalenessWeighting::Uniform => 1.0,
+            StalenessWeighting::Linear => 1.0 - age as f32 / (self.maximum_age as f32 + 1.0),
+            StalenessWeighting::Exponential(decay) => decay.powf(age as f32),
+        })
+    }
+
+    /// Removes the episodes that are too old to use with `latest_version`.
+    pub fn retain_usable(&self, episodes: &mut Vec<Episode>, latest_version: ModelVersion) {
+        episodes.retain(|episode| self.weight(episode.model_version, latest_version).is_some());
+    }
+
+    /// Scales the reward of each usable episode by its weight, after any fitness shaping.
+    pub fn discount(&self, episodes: &mut [Episode], latest_version: ModelVersion) {
+        for episode in episodes {
+            if let Some(weight) = self.weight(episode.model_version, latest_version) {
+                episode.reward *= weight;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StalenessPolicy, StalenessWeighting};
+
+    #[test]
+    fn weights_fall_with_age() {
+        let linear = StalenessPolicy {
+            weighting: StalenessWeighting::Linear,
+            maximum_age: 3,
+        };
+        assert_eq!(linear.weight(10, 10), Some(1.0));
+        assert_eq!(linear.weight(9, 10), Some(0.75));
+        assert_eq!(linear.weight(7, 10), Some(0.25));
+        assert_eq!(
+            linear.weight(6, 10),
+            None,
+            "Episodes past the limit are dropped."
+        );
+        assert_eq!(
+            linear.weight(11, 10),
+            None,
+            "Episodes from the future are dropped."
+        );
+
+        let exponential = StalenessPolicy {
+            weighting: StalenessWeighting::Exponential(0.5),
+            maximum_age: 3,
+        };
+        assert_eq!(exponential.weight(8, 10), Some(0.25));
+        let uniform = StalenessPolicy {
+            weighting: StalenessWeighting::Uniform,
+            maximum_age: 0,
+        };
+        assert_eq!(uniform.weight(10, 10), Some(1.0));
+        assert_eq!(uniform.weight(9, 10), None);
+    }
+}
@@ -0,0 +1,255 @@
+use fnv::{FnvHashMap, FnvHashSet};
+use std::collections::VecDeque;
+use std::hash::Hash;
+
+/// How many times a fresh seed is drawn again when it is already out with another worker,
+/// before the duplicate is used anyway. Only a noise table barely larger than the model leaves
+/// so few seeds that this happens.
+const MAX_DRAW_ATTEMPTS: usize = 16;
+
+/// Identifies a unit of work, so that a deadline can take back what is left of that unit alone.
+pub type UnitId = u64;
+
+/// Tracks the perturbation seeds the learner has handed out to each worker in units of work, so
+/// that no two outstanding perturbations share a seed and the seeds of a worker that leaves, or
+/// misses a deadline, are handed to the next worker that asks for work.
+pub struct WorkSchedule<W> {
+    unit_size: usize,
+    next_unit: UnitId,
+    /// Seeds each worker has been assigned and not yet returned an episode for, with their unit.
+    outstanding: FnvHashMap<W, Vec<(UnitId, u64)>>,
+    /// Seeds taken back from workers, assigned again before any fresh ones.
+    reclaimed: VecDeque<u64>,
+    /// Every seed that is outstanding or reclaimed.
+    issued: FnvHashSet<u64>,
+    /// Seeds taken back from each worker after a deadline, before it returned any episode for
+    /// them. The worker may still send those episodes, but the seeds now belong to another.
+    revoked: FnvHashMap<W, FnvHashSet<u64>>,
+}
+
+impl<W: Copy + Eq + Hash> WorkSchedule<W> {
+    pub fn new(unit_size: usize) -> WorkSchedule<W> {
+        WorkSchedule {
+            unit_size,
+            next_unit: 0,
+            outstanding: FnvHashMap::default(),
+            reclaimed: VecDeque::new(),
+            issued: FnvHashSet::default(),
+            revoked: FnvHashMap::default(),
+        }
+    }
+
+    pub fn unit_size(&self) -> usize {
+        self.unit_size
+    }
+
+    /// Assigns the next unit of work to `worker`, reclaimed seeds first and then fresh ones from
+    /// `fresh_seed`, which should draw from as wide a range as possible.
+    pub fn assign<F>(&mut self, worker: W, mut fresh_seed: F) -> (UnitId, Vec<u64>)
+    where
+        F: FnMut() -> u64,
+    {
+        let mut unit = Vec::with_capacity(self.unit_size);
+        while unit.len() < self.unit_size {
+            let seed = match self.reclaimed.pop_front() {
+                Some(seed) => seed,
+                None => {
+                    let mut seed = fresh_seed();
+                    for _ in 1..MAX_DRAW_ATTEMPTS {
+                        if !self.issued.contains(&seed) {
+                            break;
+                        }
+                        seed = fresh_seed();
+                    }
+                    self.issued.insert(seed);
+                    seed
+                }
+            };
+            unit.push(seed);
+        }
+        if let Some(revoked) = self.revoked.get_mut(&worker) {
+            for seed in &unit {
+                revoked.remove(seed);
+            }
+        }
+        let id = self.next_unit;
+        self.next_unit += 1;
+        self.outstanding
+            .entry(worker)
+            .or_default()
+            .extend(unit.iter().map(|&seed| (id, seed)));
+        (id, unit)
+    }
+
+    /// Records that `worker` returned an episode for `seed`. Returns false if the seed was not
+    /// outstanding with the worker, such as the second half of a mirrored pair.
+    pub fn complete(&mut self, worker: W, seed: u64) -> bool {
+        let seeds = match self.outstanding.get_mut(&worker) {
+            Some(seeds) => seeds,
+            None => return false,
+        };
+        match seeds
+            .iter()
+            .position(|&(_, outstanding)| outstanding == seed)
+        {
+            Some(index) => {
+                seeds.remove(index);
+                self.issued.remove(&seed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether `seed` was taken back from `worker` after a deadline, so an episode the worker
+    /// returns for it must not be used. Only seeds with no episode returned before the deadline
+    /// are revoked, so the second half of a mirrored pair that was on time is still used.
+    pub fn is_revoked(&self, worker: W, seed: u64) -> bool {
+        self.revoked
+            .get(&worker)
+            .is_some_and(|revoked| revoked.contains(&seed))
+    }
+
+    /// Takes back every seed `worker` has not returned, to be assigned to the next worker that
+    /// asks for work. Returns how many there were. The worker is done with its earlier units by
+    /// then, so it is no longer expected to send episodes for the seeds it had revoked.
+    pub fn reclaim(&mut self, worker: W) -> usize {
+        self.revoked.remove(&worker);
+        let seeds = self.outstanding.remove(&worker).unwrap_or_default();
+        let count = seeds.len();
+        self.reclaimed
+            .extend(seeds.into_iter().map(|(_, seed)| seed));
+        count
+    }
+
+    /// Takes back the seeds of `unit` that `worker` has not returned, as when the unit is past
+    /// its deadline. Returns how many there were.
+    pub fn reclaim_unit(&mut self, worker: W, unit: UnitId) -> usize {
+        let seeds = match self.outstanding.get_mut(&worker) {
+            Some(seeds) => seeds,
+            None => return 0,
+        };
+        let count = seeds.len();
+        let reclaimed = &mut self.reclaimed;
+        let revoked = self.revoked.entry(worker).or_default();
+        seeds.retain(|&(seed_unit, seed)| {
+            if seed_unit == unit {
+                reclaimed.push_back(seed);
+                revoked.insert(seed);
+            }
+            seed_unit != unit
+        });
+        count - seeds.len()
+    }
+
+    pub fn outstanding(&self, worker: W) -> usize {
+        self.outstanding.get(&worker).map_or(0, Vec::len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WorkSchedule;
+
+    #[test]
+    fn seeds_are_unique_and_reassigned() {
+        let mut schedule = WorkSchedule::<u8>::new(3);
+        let mut next_seed = 0;
+        // Hands out every seed twice, so duplicates must be skipped.
+        let mut fresh_seed = || {
+            next_seed += 1;
+            next_seed / 2
+        };
+        let (_, first) = schedule.assign(1, &mut fresh_seed);
+        let (_, second) = schedule.assign(2, &mut fresh_seed);
+        assert_eq!(first, [0, 1, 2]);
+        assert_eq!(second, [3, 4, 5]);
+
+        assert!(schedule.complete(1, 1));
+        assert!(!schedule.complete(1, 1), "Each seed completes once.");
+        assert!(!schedule.complete(2, 0), "Seeds belong to their worker.");
+        assert_eq!(schedule.outstanding(1), 2);
+
+        assert_eq!(schedule.reclaim(1), 2);
+        assert_eq!(schedule.outstanding(1), 0);
+        assert_eq!(
+            schedule.assign(2, &mut fresh_seed).1,
+            [0, 2, 6],
+            "Reclaimed seeds go out before fresh ones."
+        );
+        assert_eq!(schedule.outstanding(2), 6);
+    }
+
+    #[test]
+    fn duplicates_are_used_when_seeds_run_out() {
+        let mut schedule = WorkSchedule::<u8>::new(2);
+        assert_eq!(schedule.assign(1, || 7).1, [7, 7]);
+    }
+
+    #[test]
+    fn units_past_their_deadline_are_reassigned() {
+        let mut schedule = WorkSchedule::<u8>::new(2);
+        let mut next_seed = 0;
+        let mut fresh_seed = || {
+            next_seed += 1;
+            next_seed
+        };
+        let (first, _) = schedule.assign(1, &mut fresh_seed);
+        let (second, _) = schedule.assign(1, &mut fresh_seed);
+        assert_ne!(first, second);
+        assert!(schedule.complete(1, 1));
+
+        assert_eq!(schedule.reclaim_unit(1, first), 1);
+        assert_eq!(
+            schedule.reclaim_unit(1, first),
+            0,
+            "Each unit expires once."
+        );
+        assert_eq!(
+            schedule.reclaim_unit(2, second),
+            0,
+            "Units belong to their worker."
+        );
+        assert_eq!(
+            schedule.outstanding(1),
+            2,
+            "The worker keeps its later unit."
+        );
+        assert!(
+            !schedule.complete(1, 2),
+            "A late episode is not outstanding."
+        );
+        assert_eq!(schedule.assign(2, &mut fresh_seed).1, [2, 5]);
+    }
+
+    #[test]
+    fn late_episodes_for_reassigned_seeds_are_rejected() {
+        let mut schedule = WorkSchedule::<u8>::new(2);
+        let mut next_seed = 0;
+        let mut fresh_seed = || {
+            next_seed += 1;
+            next_seed
+        };
+        let (unit, seeds) = schedule.assign(1, &mut fresh_seed);
+        assert_eq!(seeds, [1, 2]);
+        // The first half of the mirrored pair for seed 1 arrives before the deadline.
+        assert!(schedule.complete(1, 1));
+
+        assert_eq!(schedule.reclaim_unit(1, unit), 1);
+        assert_eq!(schedule.assign(2, &mut fresh_seed).1, [2, 3]);
+        assert!(
+            schedule.is_revoked(1, 2),
+            "The late episode of worker 1 is rejected."
+        );
+        assert!(
+            !schedule.is_revoked(1, 1),
+            "The second half of a pair started on time is used."
+        );
+        assert!(!schedule.is_revoked(2, 2));
+        assert!(schedule.complete(2, 2));
+
+        // Asking for more work means the worker has sent everything from its earlier units.
+        schedule.reclaim(1);
+        assert!(!schedule.is_revoked(1, 2));
+    }
+}
@@ -1,56 +1,275 @@
+mod protocol_error;
+mod retry_policy;
 mod worker_signals;
 mod worker_thread;
 
-use crate::common::ModelVersion;
+use crate::common::{Episode, ModelVersion, NoiseSign};
+use crate::model::{
+    permute_parameters_with_noise, permute_parameters_with_seed, PerturbationSampler, SamplingMode,
+};
+use crate::noise_table::NoiseTable;
 use message_io::network::Transport;
 use pyo3::pyclass;
 use std::io;
-use worker_signals::WorkerSignal;
+use std::sync::Arc;
+use std::time::Instant;
+use worker_signals::{ThreadSignal, WorkerSignal};
+
+pub use protocol_error::ProtocolError;
+pub use retry_policy::RetryPolicy;
+pub use worker_signals::ConnectionState;
 use worker_thread::WorkerThread;
 
+/// Describes the perturbation most recently handed out by `get_parameters`, so that the reward
+/// it earned can be reported back to the learner.
+pub struct Perturbation {
+    pub model_version: ModelVersion,
+    pub noise_seed: u64,
+    pub noise_offset: usize,
+    pub noise_size: usize,
+    pub noise_std_dev: f32,
+    pub noise_sign: NoiseSign,
+}
+
+impl Perturbation {
+    pub fn into_episode(self, reward: f32) -> Episode {
+        Episode {
+            model_version: self.model_version,
+            noise_seed: self.noise_seed,
+            noise_offset: self.noise_offset,
+            noise_size: self.noise_size,
+            noise_std_dev: self.noise_std_dev,
+            noise_sign: self.noise_sign,
+            reward,
+        }
+    }
+}
+
 #[pyclass]
 pub struct Worker {
     thread: WorkerThread,
     buffer_size: Option<usize>,
+    /// The perturbed parameters handed to Python.
     pub buffer: Option<Vec<f32>>,
+    /// The unperturbed policy most recently received from the learner.
+    pub policy: Option<Vec<f32>>,
     pub model_version: Option<ModelVersion>,
+    pub perturbation: Option<Perturbation>,
+    pub sampler: PerturbationSampler,
+    /// The model version the learner's latest perturbations are meant for, they wait for a
+    /// policy at least this recent.
+    assigned_version: Option<ModelVersion>,
+    /// Shared noise table, perturbations are read from it instead of generated when present.
+    pub noise_table: Option<Arc<NoiseTable>>,
+    /// The first protocol error since it was last reported to Python.
+    pub protocol_error: Option<ProtocolError>,
+    pub connection_state: ConnectionState,
+    /// Noise standard deviation requested by the learner.
+    learner_noise_std_dev: Option<f32>,
+    /// Noise standard deviation chosen for this worker, takes precedence over the learner's.
+    noise_std_dev_override: Option<f32>,
 }
 
 impl Worker {
-    pub fn new(transport: Transport, addr: String) -> io::Result<Worker> {
-        let thread = WorkerThread::new(transport, addr)?;
+    pub fn new(
+        transport: Transport,
+        addr: String,
+        noise_std_dev: Option<f32>,
+        sampling_mode: SamplingMode,
+        retry_policy: RetryPolicy,
+    ) -> io::Result<Worker> {
+        let thread = WorkerThread::new(transport, addr, retry_policy)?;
         Ok(Worker {
             thread,
             buffer_size: None,
             buffer: None,
+            policy: None,
             model_version: None,
+            perturbation: None,
+            sampler: PerturbationSampler::new(sampling_mode),
+            assigned_version: None,
+            noise_table: None,
+            protocol_error: None,
+            connection_state: ConnectionState::Connecting,
+            learner_noise_std_dev: None,
+            noise_std_dev_override: noise_std_dev,
         })
     }
 
+    pub fn noise_std_dev(&self) -> Option<f32> {
+        self.noise_std_dev_override.or(self.learner_noise_std_dev)
+    }
+
+    /// Fills the worker's own buffer with a new perturbation of the policy, see `perturb_into`.
+    pub fn perturb(&mut self) -> Option<&[f32]> {
+        let mut buffer = self.buffer.take()?;
+        let perturbed = self.perturb_into(&mut buffer);
+        self.buffer = Some(buffer);
+        if perturbed {
+            self.buffer.as_deref()
+        } else {
+            None
+        }
+    }
+
+    /// Fills `buffer` with a new perturbation of the policy and remembers how it was made.
+    /// Returns false until the learner has sent a policy, while waiting for the learner to
+    /// assign more perturbations, or if `buffer` is the wrong size.
+    pub fn perturb_into(&mut self, buffer: &mut [f32]) -> bool {
+        if !self.can_perturb() {
+            return false;
+        }
+        let (policy, model_version, noise_std_dev) =
+            match (&self.policy, self.model_version, self.noise_std_dev()) {
+                (Some(policy), Some(model_version), Some(noise_std_dev))
+                    if policy.len() == buffer.len() =>
+                {
+                    (policy, model_version, noise_std_dev)
+                }
+                _ => return false,
+            };
+        let noise_size = buffer.len();
+
+        // The sampler's draw is the noise seed, or picks the offset when there is a noise table.
+        let (draw, noise_sign) = match self.sampler.next_perturbation() {
+            Some(perturbation) => perturbation,
+            None => return false,
+        };
+        let table_noise = self.noise_table.as_ref().and_then(|table| {
+            let offset = table.offset_for(draw, noise_size)?;
+            Some((table.seed(), offset, table.get(offset, noise_size)?))
+        });
+        let (noise_seed, noise_offset) = match table_noise {
+            Some((table_seed, offset, noise)) => {
+                permute_parameters_with_noise(policy, noise, buffer, noise_std_dev, noise_sign);
+                (table_seed, offset)
+            }
+            None => {
+                permute_parameters_with_seed(policy, buffer, noise_std_dev, draw, noise_sign);
+                (draw, 0)
+            }
+        };
+
+        self.perturbation = Some(Perturbation {
+            model_version,
+            noise_seed,
+            noise_offset,
+            noise_size,
+            noise_std_dev,
+            noise_sign,
+        });
+        true
+    }
+
+    /// Queues the reward for the last perturbation to be sent to the learner.
+    /// Returns false if there is no perturbation awaiting a reward.
+    pub fn queue_episode(&mut self, reward: f32) -> bool {
+        match self.perturbation.take() {
+            Some(perturbation) => {
+                let episode = perturbation.into_episode(reward);
+                let signals = self.thread.handler.signals();
+                signals.send(ThreadSignal::SendEpisode(episode));
+                if self.sampler.is_waiting_for_seeds() {
+                    signals.send(ThreadSignal::RequestPerturbations);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// True once there is a policy that `perturb` can hand out.
+    pub fn has_policy(&self) -> bool {
+        self.policy.is_some() && self.model_version.is_some() && self.noise_std_dev().is_some()
+    }
+
+    /// True if `perturb` can hand out a perturbation now, the learner has sent a policy recent
+    /// enough for the perturbations it assigned and they are not all used up.
+    pub fn can_perturb(&self) -> bool {
+        self.has_policy()
+            && !self.sampler.is_waiting_for_seeds()
+            && self.model_version >= self.assigned_version
+    }
+
     pub fn process_signals(&mut self) {
-        let signal_receiver = &mut self.thread.receiver;
-        while let Some(signal) = signal_receiver.try_receive() {
-            match signal {
-                WorkerSignal::ConfigureBuffer(size) => {
+        while let Some(signal) = self.thread.receiver.try_receive() {
+            self.handle_signal(signal);
+        }
+    }
+
+    /// Handles signals from the worker thread as they arrive until `ready` holds or a protocol
+    /// error is reported. Returns false if `deadline` passes first.
+    pub fn wait_until<F>(&mut self, deadline: Instant, ready: F) -> bool
+    where
+        F: Fn(&Worker) -> bool,
+    {
+        self.process_signals();
+        while !(ready(self) || self.protocol_error.is_some()) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.thread.receiver.receive_timeout(remaining) {
+                Some(signal) => self.handle_signal(signal),
+                None => return false,
+            }
+        }
+        true
+    }
+
+    fn handle_signal(&mut self, signal: WorkerSignal) {
+        match signal {
+            WorkerSignal::ConfigureBuffer(size) => {
+                // A learner that restarted with a different model size invalidates the policy.
+                if self.buffer_size != Some(size) {
                     self.buffer_size = Some(size);
                     self.buffer = Some(vec![0.0; size]);
+                    self.policy = None;
+                    self.model_version = None;
+                    self.perturbation = None;
                 }
-                WorkerSignal::ModelUpdate(version, data) => {
-                    if let Some(buffer) = &mut self.buffer {
-                        if buffer.len() != data.len() {
-                            panic!(
-                                "Illegal state: ModelUpdate size {} does not match buffer size {}",
-                                data.len(),
-                                buffer.len()
-                            );
-                        }
-                        buffer.copy_from_slice(&data);
-                    } else {
-                        panic!("Illegal state: ModelUpdate received before buffer configured");
-                    }
+            }
+            WorkerSignal::ConfigureNoise(noise_std_dev) => {
+                self.learner_noise_std_dev = Some(noise_std_dev);
+            }
+            WorkerSignal::ConfigureNoiseTable(noise_table) => {
+                self.noise_table = noise_table;
+            }
+            WorkerSignal::ModelUpdate(version, data) => match self.buffer_size {
+                Some(buffer_size) if buffer_size == data.len() => {
+                    self.policy = Some(data);
                     self.model_version = Some(version);
                 }
+                Some(buffer_size) => {
+                    self.report_error(ProtocolError::ModelSizeMismatch {
+                        model_version: version,
+                        expected: buffer_size,
+                        received: data.len(),
+                    });
+                }
+                None => self.report_error(ProtocolError::NotInitialised),
+            },
+            WorkerSignal::AssignPerturbations {
+                model_version,
+                noise_std_dev,
+                noise_seeds,
+            } => {
+                self.learner_noise_std_dev = Some(noise_std_dev);
+                self.assigned_version = Some(model_version);
+                self.sampler.assign(noise_seeds);
             }
+            WorkerSignal::ProtocolError(error) => self.report_error(error),
+            WorkerSignal::ConnectionState(state) => {
+                if state == ConnectionState::Disconnected {
+                    // The learner takes back unevaluated seeds when a worker disconnects.
+                    self.sampler.reset();
+                    self.assigned_version = None;
+                }
+                self.connection_state = state;
+            }
+        }
+    }
+
+    fn report_error(&mut self, error: ProtocolError) {
+        if self.protocol_error.is_none() {
+            self.protocol_error = Some(error);
         }
     }
 }
@@ -0,0 +1,115 @@
+use crate::common::ModelVersion;
+use crate::noise::NoiseSpec;
+use std::fmt;
+
+/// Something the learner sent could not be used. The worker thread recovers from these by
+/// discarding its transfer and asking the learner for a fresh copy of the model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    InvalidMessage(String),
+    NotInitialised,
+    TransferStartedMidway {
+        model_version: ModelVersion,
+        chunk_offset: usize,
+    },
+    TransferOffsetMismatch {
+        model_version: ModelVersion,
+        expected: usize,
+        received: usize,
+    },
+    ChunkOutOfBounds {
+        model_version: ModelVersion,
+        chunk_offset: usize,
+        chunk_len: usize,
+        parameter_count: usize,
+    },
+    ModelSizeMismatch {
+        model_version: ModelVersion,
+        expected: usize,
+        received: usize,
+    },
+    ModelDigestMismatch {
+        model_version: ModelVersion,
+        expected: u64,
+        received: u64,
+    },
+    UnsupportedNoiseSpec {
+        version: u32,
+    },
+}
+
+impl ProtocolError {
+    /// Whether the worker cannot work with this learner at all, so there is nothing to recover.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, ProtocolError::UnsupportedNoiseSpec { .. })
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::InvalidMessage(reason) => {
+                write!(f, "Invalid message from learner: {}", reason)
+            }
+            ProtocolError::NotInitialised => {
+                write!(
+                    f,
+                    "Parameter transfer started before worker was initialised"
+                )
+            }
+            ProtocolError::TransferStartedMidway {
+                model_version,
+                chunk_offset,
+            } => write!(
+                f,
+                "Transfer of model version {} began with chunk_offset({}) > 0",
+                model_version, chunk_offset
+            ),
+            ProtocolError::TransferOffsetMismatch {
+                model_version,
+                expected,
+                received,
+            } => write!(
+                f,
+                "Transfer of model version {} expected chunk_offset {}, received {}",
+                model_version, expected, received
+            ),
+            ProtocolError::ChunkOutOfBounds {
+                model_version,
+                chunk_offset,
+                chunk_len,
+                parameter_count,
+            } => write!(
+                f,
+                "Chunk of {} parameters at offset {} overruns model version {} of {} parameters",
+                chunk_len, chunk_offset, model_version, parameter_count
+            ),
+            ProtocolError::ModelSizeMismatch {
+                model_version,
+                expected,
+                received,
+            } => write!(
+                f,
+                "Model version {} has {} parameters, expected {}",
+                model_version, received, expected
+            ),
+            ProtocolError::ModelDigestMismatch {
+                model_version,
+                expected,
+                received,
+            } => write!(
+                f,
+                "Model version {} has digest {:016x}, expected {:016x}",
+                model_version, received, expected
+            ),
+            ProtocolError::UnsupportedNoiseSpec { version } => write!(
+                f,
+                "Learner generates noise with version {} of the noise spec, this worker supports version {}",
+                version,
+                NoiseSpec::CURRENT.version
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
@@ -0,0 +1,78 @@
+use rand::Rng;
+use std::time::Duration;
+
+/// How long the worker waits between attempts to reconnect to the learner.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Give up after this many consecutive failed attempts, None retries forever.
+    pub max_retries: Option<u32>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            max_retries: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn allows_retry(&self, failed_attempts: u32) -> bool {
+        self.max_retries
+            .is_none_or(|max_retries| failed_attempts < max_retries)
+    }
+
+    /// Exponential backoff with equal jitter: half of the delay is fixed and half is random, so
+    /// workers dropped by the same learner restart do not all reconnect at once.
+    pub fn delay<R: Rng>(&self, failed_attempts: u32, rng: &mut R) -> Duration {
+        let backoff =
+            self.initial_delay.as_secs_f64() * 2.0_f64.powi(failed_attempts.min(32) as i32);
+        let backoff = backoff.min(self.max_delay.as_secs_f64());
+        Duration::from_secs_f64(backoff / 2.0 + rng.gen_range(0.0..=backoff / 2.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetryPolicy;
+    use rand::SeedableRng;
+    use rand_xoshiro::Xoroshiro128Plus;
+    use std::time::Duration;
+
+    #[test]
+    fn delay_grows_and_is_capped() {
+        let policy = RetryPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            max_retries: Some(3),
+        };
+        let mut rng = Xoroshiro128Plus::seed_from_u64(0);
+        for (attempt, backoff) in [
+            (0, 1.0),
+            (1, 2.0),
+            (2, 4.0),
+            (3, 8.0),
+            (4, 10.0),
+            (100, 10.0),
+        ] {
+            for _ in 0..100 {
+                let delay = policy.delay(attempt, &mut rng).as_secs_f64();
+                assert!(
+                    delay >= backoff / 2.0 && delay <= backoff,
+                    "Delay {} for attempt {} should be within [{}, {}]",
+                    delay,
+                    attempt,
+                    backoff / 2.0,
+                    backoff
+                );
+            }
+        }
+        assert!(policy.allows_retry(2));
+        assert!(!policy.allows_retry(3));
+        assert!(RetryPolicy::default().allows_retry(u32::MAX));
+    }
+}
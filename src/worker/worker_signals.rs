@@ -1,11 +1,51 @@
-use crate::common::ModelVersion;
+use crate::common::{Episode, ModelVersion};
+use crate::noise_table::NoiseTable;
+use std::sync::Arc;
+
+use super::protocol_error::ProtocolError;
 
 pub enum ThreadSignal {
     SendInit,
+    SendEpisode(Episode),
+    /// Every assigned perturbation has been evaluated, ask the learner for more.
+    RequestPerturbations,
+    Reconnect,
     Stop,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Waiting for a connection attempt to the learner to complete.
+    Connecting,
+    /// Connected, waiting for the learner to initialise the worker.
+    Initialising,
+    /// Initialised by the learner.
+    Ready,
+    /// Waiting to reconnect, or given up after exhausting the retry policy.
+    Disconnected,
+}
+
+impl ConnectionState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Initialising => "initialising",
+            ConnectionState::Ready => "ready",
+            ConnectionState::Disconnected => "disconnected",
+        }
+    }
+}
+
 pub enum WorkerSignal {
     ModelUpdate(ModelVersion, Vec<f32>),
     ConfigureBuffer(usize),
+    ConfigureNoise(f32),
+    ConfigureNoiseTable(Option<Arc<NoiseTable>>),
+    AssignPerturbations {
+        model_version: ModelVersion,
+        noise_std_dev: f32,
+        noise_seeds: Vec<u64>,
+    },
+    ProtocolError(ProtocolError),
+    ConnectionState(ConnectionState),
 }
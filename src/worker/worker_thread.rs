@@ -1,10 +1,14 @@
 use crate::common::{
-    MessageFromLearner, MessageFromWorker, ModelTransfer, ModelVersion, ParameterChunkData,
-    TransferCompletion,
+    chunk_hash, model_digest, MessageFromLearner, MessageFromWorker, ModelDelta, ModelTransfer,
+    ModelVersion, ParameterChunkData, TransferCompletion,
 };
+use crate::noise_table::NoiseTable;
 use message_io::{events, network, network::NetEvent, node};
+use std::sync::Arc;
 use std::{io, thread};
 
+use super::protocol_error::ProtocolError;
+use super::retry_policy::RetryPolicy;
 use super::worker_signals::*;
 
 type WorkerHandler = node::NodeHandler<ThreadSignal>;
@@ -16,7 +20,21 @@ type NodeEvent<'a> = node::NodeEvent<'a, ThreadSignal>;
 #[derive(Default)]
 struct WorkerThreadData {
     parameter_count: Option<usize>,
+    noise_table: Option<Arc<NoiseTable>>,
+    /// The latest complete model, kept so that deltas from the learner can be applied to it.
+    model: Option<(ModelVersion, Vec<f32>)>,
     transfer: Option<ModelTransfer>,
+    /// Set while waiting for the learner to resend a chunk that failed its hash check.
+    retransmit_requested: bool,
+}
+
+/// Where the learner is and how to get back to it when the connection drops.
+struct Connection {
+    transport: network::Transport,
+    addr: String,
+    server: network::Endpoint,
+    retry_policy: RetryPolicy,
+    failed_attempts: u32,
 }
 
 pub struct WorkerThread {
@@ -31,7 +49,11 @@ impl Drop for WorkerThread {
 }
 
 impl WorkerThread {
-    pub fn new(transport: network::Transport, addr: String) -> io::Result<WorkerThread> {
+    pub fn new(
+        transport: network::Transport,
+        addr: String,
+        retry_policy: RetryPolicy,
+    ) -> io::Result<WorkerThread> {
         // Create event sender and receiver pair for internal communication between background thread and worker.
         let receiver = events::EventReceiver::default();
         let sender = receiver.sender().clone();
@@ -39,12 +61,19 @@ impl WorkerThread {
         // Handler is also an event sender, listener is an event receiver.
         // This pair is used to communicate with the remote server.
         let (handler, listener) = node::split::<ThreadSignal>();
-        let (server, _) = handler.network().connect(transport, addr)?;
+        let (server, _) = handler.network().connect(transport, addr.clone())?;
+        let connection = Connection {
+            transport,
+            addr,
+            server,
+            retry_policy,
+            failed_attempts: 0,
+        };
         // Handler is an Arc internally, so we can clone it and reuse it for the background thread.
         let thread_handler = handler.clone();
         // Spawn the worker thread to handle the network connection, keeping the thread handle.
         thread::spawn(move || {
-            worker_thread_main(server, thread_handler, listener, sender);
+            worker_thread_main(connection, thread_handler, listener, sender);
         });
 
         Ok(WorkerThread { handler, receiver })
@@ -52,60 +81,90 @@ impl WorkerThread {
 }
 
 fn worker_thread_main(
-    server: network::Endpoint,
+    mut connection: Connection,
     handler: WorkerHandler,
     listener: WorkerListener,
     sender: WorkerEventSender,
 ) {
     let mut thread_data = WorkerThreadData::default();
+    sender.send(WorkerSignal::ConnectionState(ConnectionState::Connecting));
     listener.for_each(move |event| match event {
         NodeEvent::Network(net_event) => match net_event {
-            NetEvent::Connected(_endpoint, _ok) => handler.signals().send(ThreadSignal::SendInit),
+            // Events from endpoints of earlier connection attempts are ignored.
+            NetEvent::Connected(endpoint, _) if endpoint != connection.server => (),
+            NetEvent::Connected(_endpoint, true) => {
+                println!("Connected to learner at {}", connection.addr);
+                connection.failed_attempts = 0;
+                sender.send(WorkerSignal::ConnectionState(ConnectionState::Initialising));
+                handler.signals().send(ThreadSignal::SendInit);
+            }
+            NetEvent::Connected(_endpoint, false) => {
+                println!("Could not connect to learner at {}", connection.addr);
+                schedule_reconnect(&handler, &sender, &mut connection);
+            }
             NetEvent::Accepted(_, _) => unreachable!(), // Only generated by listening
-            NetEvent::Message(_endpoint, data) => {
-                let message: MessageFromLearner = bincode::deserialize(data).unwrap();
-                match message {
-                    MessageFromLearner::InitialiseWorker { parameter_count } => {
-                        thread_data.parameter_count = Some(parameter_count);
-                        sender.send(WorkerSignal::ConfigureBuffer(parameter_count));
+            NetEvent::Message(endpoint, data) if endpoint == connection.server => {
+                let server = connection.server;
+                match handle_learner_message(&handler, server, &sender, &mut thread_data, data) {
+                    Ok(()) => (),
+                    Err(error) if error.is_fatal() => {
+                        println!(
+                            "Protocol error: {}, disconnecting from learner at {}",
+                            error, connection.addr
+                        );
+                        handler.network().remove(server.resource_id());
+                        sender.send(WorkerSignal::ConnectionState(ConnectionState::Disconnected));
+                        sender.send(WorkerSignal::ProtocolError(error));
                     }
-                    MessageFromLearner::ParameterChunk {
-                        model_version,
-                        data,
-                    } => {
-                        thread_data.transfer =
-                            match handle_transfer(&mut thread_data, model_version, data) {
-                                TransferCompletion::Complete {
-                                    model_version,
-                                    model,
-                                } => {
-                                    sender.send(WorkerSignal::ModelUpdate(model_version, model));
-                                    None
-                                }
-                                TransferCompletion::NeedsMoreData {
-                                    transfer,
-                                    received,
-                                    total,
-                                } => {
-                                    println!(
-                                        "Receiving model (version {}): {}/{}",
-                                        model_version, received, total
-                                    );
-                                    Some(transfer)
-                                }
-                            }
+                    Err(error) => {
+                        println!("Protocol error: {}", error);
+                        recover_from_protocol_error(&handler, server, &mut thread_data);
+                        sender.send(WorkerSignal::ProtocolError(error));
                     }
                 }
             }
-            NetEvent::Disconnected(_endpoint) => (),
+            NetEvent::Message(_, _) => (),
+            NetEvent::Disconnected(endpoint) if endpoint == connection.server => {
+                println!("Disconnected from learner at {}", connection.addr);
+                // Whatever the learner was sending is lost, it re-initialises us on reconnect.
+                thread_data = WorkerThreadData::default();
+                schedule_reconnect(&handler, &sender, &mut connection);
+            }
+            NetEvent::Disconnected(_) => (),
         },
         NodeEvent::Signal(signal) => match signal {
             ThreadSignal::SendInit => {
-                let init_message = MessageFromWorker::Init {};
-                let init_message_bytes = bincode::serialize(&init_message).unwrap();
-                handler
+                send_message(&handler, connection.server, &MessageFromWorker::Init {});
+            }
+            ThreadSignal::SendEpisode(episode) => {
+                send_message(
+                    &handler,
+                    connection.server,
+                    &MessageFromWorker::EpisodeCompleted(episode),
+                );
+            }
+            ThreadSignal::RequestPerturbations => {
+                send_message(
+                    &handler,
+                    connection.server,
+                    &MessageFromWorker::RequestPerturbations,
+                );
+            }
+            ThreadSignal::Reconnect => {
+                sender.send(WorkerSignal::ConnectionState(ConnectionState::Connecting));
+                match handler
                     .network()
-                    .send(server, init_message_bytes.as_slice());
+                    .connect(connection.transport, connection.addr.clone())
+                {
+                    Ok((server, _)) => connection.server = server,
+                    Err(error) => {
+                        println!(
+                            "Could not connect to learner at {}: {}",
+                            connection.addr, error
+                        );
+                        schedule_reconnect(&handler, &sender, &mut connection);
+                    }
+                }
             }
             ThreadSignal::Stop => {
                 // Stops the listener, which will cause listener.for_each to return, and the thread to exit.
@@ -115,39 +174,405 @@ fn worker_thread_main(
     });
 }
 
+fn schedule_reconnect(
+    handler: &WorkerHandler,
+    sender: &WorkerEventSender,
+    connection: &mut Connection,
+) {
+    sender.send(WorkerSignal::ConnectionState(ConnectionState::Disconnected));
+    if !connection
+        .retry_policy
+        .allows_retry(connection.failed_attempts)
+    {
+        println!(
+            "Giving up on learner at {} after {} attempts",
+            connection.addr, connection.failed_attempts
+        );
+        return;
+    }
+    let delay = connection
+        .retry_policy
+        .delay(connection.failed_attempts, &mut rand::thread_rng());
+    connection.failed_attempts += 1;
+    println!(
+        "Reconnecting to learner in {:.2} seconds",
+        delay.as_secs_f64()
+    );
+    handler
+        .signals()
+        .send_with_timer(ThreadSignal::Reconnect, delay);
+}
+
+fn send_message(handler: &WorkerHandler, server: network::Endpoint, message: &MessageFromWorker) {
+    let message_bytes = bincode::serialize(message).unwrap();
+    handler.network().send(server, message_bytes.as_slice());
+}
+
+fn handle_learner_message(
+    handler: &WorkerHandler,
+    server: network::Endpoint,
+    sender: &WorkerEventSender,
+    thread_data: &mut WorkerThreadData,
+    data: &[u8],
+) -> Result<(), ProtocolError> {
+    let message: MessageFromLearner = bincode::deserialize(data)
+        .map_err(|error| ProtocolError::InvalidMessage(error.to_string()))?;
+    match message {
+        MessageFromLearner::InitialiseWorker {
+            parameter_count,
+            noise_std_dev,
+            noise_spec,
+            noise_table,
+        } => {
+            if !noise_spec.is_supported() {
+                return Err(ProtocolError::UnsupportedNoiseSpec {
+                    version: noise_spec.version,
+                });
+            }
+            thread_data.parameter_count = Some(parameter_count);
+            thread_data.model = None;
+            sender.send(WorkerSignal::ConfigureBuffer(parameter_count));
+            sender.send(WorkerSignal::ConfigureNoise(noise_std_dev));
+            // Regenerate the learner's noise table here so Python never waits on it.
+            let noise_table = noise_table.map(|config| Arc::new(NoiseTable::new(config)));
+            thread_data.noise_table = noise_table.clone();
+            sender.send(WorkerSignal::ConfigureNoiseTable(noise_table));
+            sender.send(WorkerSignal::ConnectionState(ConnectionState::Ready));
+        }
+        MessageFromLearner::ParameterChunk {
+            model_version,
+            model_digest,
+            data,
+        } => {
+            if !accept_chunk(handler, server, thread_data, model_version, &data) {
+                return Ok(());
+            }
+            let completion = handle_transfer(thread_data, model_version, model_digest, data)?;
+            thread_data.transfer = match completion {
+                TransferCompletion::Complete {
+                    model_version,
+                    model,
+                } => {
+                    acknowledge_model(handler, server, model_version, model_digest);
+                    thread_data.model = Some((model_version, model.clone()));
+                    sender.send(WorkerSignal::ModelUpdate(model_version, model));
+                    None
+                }
+                TransferCompletion::DigestMismatch {
+                    model_version,
+                    expected,
+                    received,
+                } => {
+                    return Err(ProtocolError::ModelDigestMismatch {
+                        model_version,
+                        expected,
+                        received,
+                    });
+                }
+                TransferCompletion::NeedsMoreData {
+                    transfer,
+                    received,
+                    total,
+                } => {
+                    println!(
+                        "Receiving model (version {}): {}/{}",
+                        model_version, received, total
+                    );
+                    Some(transfer)
+                }
+            }
+        }
+        MessageFromLearner::AssignPerturbations {
+            model_version,
+            noise_std_dev,
+            noise_seeds,
+        } => {
+            sender.send(WorkerSignal::AssignPerturbations {
+                model_version,
+                noise_std_dev,
+                noise_seeds,
+            });
+        }
+        MessageFromLearner::ModelDelta {
+            model_version,
+            delta,
+        } => {
+            // A full model is already on its way when a delta cannot be applied.
+            let awaiting_model = thread_data.transfer.is_some() || thread_data.retransmit_requested;
+            match apply_delta(thread_data, model_version, &delta) {
+                Some(model) => {
+                    acknowledge_model(handler, server, model_version, delta.model_digest);
+                    sender.send(WorkerSignal::ModelUpdate(model_version, model.to_vec()));
+                }
+                None if awaiting_model => (),
+                None => {
+                    println!(
+                        "Could not rebuild model version {} from its delta, requesting it in full.",
+                        model_version
+                    );
+                    send_message(handler, server, &MessageFromWorker::RequestModel);
+                    thread_data.retransmit_requested = true;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Rebuilds `model_version` from the previous version and the delta between them. Returns None
+/// if the worker does not hold the previous version, or if the result fails its hash check, in
+/// which case the model is discarded.
+fn apply_delta<'a>(
+    thread_data: &'a mut WorkerThreadData,
+    model_version: ModelVersion,
+    delta: &ModelDelta,
+) -> Option<&'a [f32]> {
+    let (version, model) = thread_data.model.as_mut()?;
+    if *version + 1 != model_version {
+        return None;
+    }
+    let gradient = delta
+        .gradient
+        .expand(model.len(), thread_data.noise_table.as_deref());
+    delta.step.apply(model, &gradient);
+    if model_digest(model) != delta.model_digest {
+        thread_data.model = None;
+        return None;
+    }
+    *version = model_version;
+    thread_data
+        .model
+        .as_ref()
+        .map(|(_, model)| model.as_slice())
+}
+
+/// Tells the learner which version of the model the worker is now running.
+fn acknowledge_model(
+    handler: &WorkerHandler,
+    server: network::Endpoint,
+    model_version: ModelVersion,
+    model_digest: u64,
+) {
+    let message = MessageFromWorker::ModelReceived {
+        model_version,
+        model_digest,
+    };
+    send_message(handler, server, &message);
+}
+
+/// Discards any partial transfer and asks the learner to start again, re-initialising first if
+/// the learner never told us the model size.
+fn recover_from_protocol_error(
+    handler: &WorkerHandler,
+    server: network::Endpoint,
+    thread_data: &mut WorkerThreadData,
+) {
+    thread_data.transfer = None;
+    if thread_data.parameter_count.is_none() {
+        send_message(handler, server, &MessageFromWorker::Init {});
+    } else {
+        send_message(handler, server, &MessageFromWorker::RequestModel);
+        // Chunks of the abandoned transfer are dropped until the new one starts.
+        thread_data.retransmit_requested = true;
+    }
+}
+
+/// Checks a chunk's hash before it joins the transfer, asking the learner to resend it if it
+/// was corrupted. Chunks already in flight when the request was made are dropped until the
+/// resent chunk arrives.
+fn accept_chunk(
+    handler: &WorkerHandler,
+    server: network::Endpoint,
+    thread_data: &mut WorkerThreadData,
+    model_version: ModelVersion,
+    data: &ParameterChunkData,
+) -> bool {
+    let expected_offset = thread_data
+        .transfer
+        .as_ref()
+        .map_or(0, |transfer| transfer.transfer_offset);
+    if thread_data.retransmit_requested
+        && data.chunk_offset != expected_offset
+        && data.chunk_offset != 0
+    {
+        return false;
+    }
+
+    if chunk_hash(&data.chunk) != data.chunk_hash {
+        println!(
+            "Chunk at offset {} of model version {} failed its hash check, requesting it again.",
+            data.chunk_offset, model_version
+        );
+        let request = MessageFromWorker::RequestChunk {
+            model_version,
+            chunk_offset: data.chunk_offset,
+        };
+        let request_bytes = bincode::serialize(&request).unwrap();
+        handler.network().send(server, request_bytes.as_slice());
+        thread_data.retransmit_requested = true;
+        return false;
+    }
+
+    thread_data.retransmit_requested = false;
+    true
+}
+
 fn handle_transfer(
     thread_data: &mut WorkerThreadData,
     model_version: ModelVersion,
+    model_digest: u64,
     data: ParameterChunkData,
-) -> TransferCompletion {
+) -> Result<TransferCompletion, ProtocolError> {
     let transfer = Option::take(&mut thread_data.transfer);
     match (thread_data.parameter_count, transfer) {
         // First chunk received, initialised transfer.
         (Some(parameter_count), None) => {
             if data.chunk_offset != 0 {
-                panic!(
-                    "Illegal state: Transfer began with chunk_offset({}) > 0",
-                    data.chunk_offset
-                );
+                return Err(ProtocolError::TransferStartedMidway {
+                    model_version,
+                    chunk_offset: data.chunk_offset,
+                });
             }
             println!("Beginning transfer of {} parameters", parameter_count);
-            thread_data.transfer = Some(ModelTransfer::new(model_version, parameter_count));
+            thread_data.transfer = Some(ModelTransfer::new(
+                model_version,
+                model_digest,
+                parameter_count,
+            ));
             // Recursive call to handle the chunk
-            handle_transfer(thread_data, model_version, data)
+            handle_transfer(thread_data, model_version, model_digest, data)
+        }
+        // The learner restarted the transfer, possibly with a newer model, discard the old one.
+        (Some(_), Some(transfer)) if data.chunk_offset == 0 && transfer.transfer_offset != 0 => {
+            println!(
+                "Transfer of model version {} replaced by version {}",
+                transfer.model_version, model_version
+            );
+            handle_transfer(thread_data, model_version, model_digest, data)
         }
         // Received chunk object and transfer is initialised
-        (Some(_), Some(transfer)) => {
+        (Some(parameter_count), Some(transfer)) => {
             if transfer.transfer_offset != data.chunk_offset {
-                panic!("Illegal state: transfer_offset mismatch.");
+                return Err(ProtocolError::TransferOffsetMismatch {
+                    model_version,
+                    expected: transfer.transfer_offset,
+                    received: data.chunk_offset,
+                });
+            }
+            if data.chunk_offset + data.chunk.len() > parameter_count {
+                return Err(ProtocolError::ChunkOutOfBounds {
+                    model_version,
+                    chunk_offset: data.chunk_offset,
+                    chunk_len: data.chunk.len(),
+                    parameter_count,
+                });
             }
-            transfer.receive_chunk(&data.chunk)
+            Ok(transfer.receive_chunk(&data.chunk))
         }
-        // Illegal states.
-        (None, Some(_)) => {
-            panic!("Illegal state: parameter_count not set but active transfer exists.");
+        // Illegal states, a transfer cannot exist without a parameter count.
+        (None, _) => Err(ProtocolError::NotInitialised),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_delta, handle_transfer, ProtocolError, WorkerThreadData};
+    use crate::common::{model_digest, Episode, ModelDelta, NoiseSign, ParameterChunkData};
+    use crate::gradient::NoiseGradient;
+    use crate::noise_table::{NoiseTable, NoiseTableConfig};
+    use crate::optimizer::{Optimizer, OptimizerConfig, WeightDecay};
+    use std::sync::Arc;
+
+    fn chunk(chunk_offset: usize, len: usize) -> ParameterChunkData {
+        ParameterChunkData {
+            chunk: vec![1.0; len],
+            chunk_offset,
+            chunk_hash: 0,
         }
-        (None, None) => {
-            panic!("Illegal state: parameter transfer started before worker was initialised.");
+    }
+
+    #[test]
+    fn illegal_transfers_are_errors() {
+        let mut thread_data = WorkerThreadData::default();
+        assert_eq!(
+            handle_transfer(&mut thread_data, 0, 0, chunk(0, 4)).err(),
+            Some(ProtocolError::NotInitialised)
+        );
+
+        thread_data.parameter_count = Some(10);
+        assert_eq!(
+            handle_transfer(&mut thread_data, 0, 0, chunk(4, 4)).err(),
+            Some(ProtocolError::TransferStartedMidway {
+                model_version: 0,
+                chunk_offset: 4
+            })
+        );
+        assert!(handle_transfer(&mut thread_data, 0, 0, chunk(0, 11)).is_err());
+    }
+
+    #[test]
+    fn deltas_rebuild_the_learners_model() {
+        const PARAMETER_COUNT: usize = 1000;
+        let noise_table = Arc::new(NoiseTable::new(NoiseTableConfig {
+            seed: 5,
+            size: 2 * PARAMETER_COUNT,
+        }));
+        // One episode from the table and one regenerated from its own seed.
+        let episodes: Vec<Episode> = [(5, 17, 1.5), (9, 0, -0.5)]
+            .into_iter()
+            .map(|(noise_seed, noise_offset, reward)| Episode {
+                model_version: 0,
+                noise_seed,
+                noise_offset,
+                noise_size: PARAMETER_COUNT,
+                noise_std_dev: 0.1,
+                noise_sign: NoiseSign::Negative,
+                reward,
+            })
+            .collect();
+
+        let base_model: Vec<f32> = (0..PARAMETER_COUNT).map(|i| i as f32 / 100.0).collect();
+        let mut optimizer = OptimizerConfig {
+            weight_decay: WeightDecay::Decoupled(0.1),
+            ..OptimizerConfig::default()
         }
+        .build(PARAMETER_COUNT);
+        let noise_gradient = NoiseGradient::from_episodes(&episodes, PARAMETER_COUNT);
+        let mut learner_model = base_model.clone();
+        optimizer.step(
+            &mut learner_model,
+            &noise_gradient.expand(PARAMETER_COUNT, Some(&noise_table)),
+        );
+        let delta = ModelDelta {
+            gradient: noise_gradient,
+            step: optimizer.stateless_step().unwrap(),
+            model_digest: model_digest(&learner_model),
+        };
+
+        let mut thread_data = WorkerThreadData {
+            noise_table: Some(noise_table),
+            ..WorkerThreadData::default()
+        };
+        assert!(
+            apply_delta(&mut thread_data, 1, &delta).is_none(),
+            "A worker without a model cannot apply a delta."
+        );
+        thread_data.model = Some((0, base_model.clone()));
+        assert!(apply_delta(&mut thread_data, 2, &delta).is_none());
+        assert_eq!(
+            apply_delta(&mut thread_data, 1, &delta),
+            Some(learner_model.as_slice())
+        );
+
+        thread_data.model = Some((0, base_model));
+        let corrupted = ModelDelta {
+            model_digest: delta.model_digest ^ 1,
+            ..delta
+        };
+        assert!(apply_delta(&mut thread_data, 1, &corrupted).is_none());
+        assert!(
+            thread_data.model.is_none(),
+            "A model that fails its hash check is discarded."
+        );
     }
 }